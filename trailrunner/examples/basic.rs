@@ -29,29 +29,24 @@ impl TApp<User> for App {
         &mut self.message_queue
     }
 
-    fn receive(&mut self, id: MessageId, from_peer: PeerId, message: &Self::Message) {
+    fn receive(&mut self, id: MessageId, from_peer: PeerId, message: &Self::Message) -> ReceiveOutcome {
         match message {
             MyMessage::String(s) => {
                 info!("Received message {} from peer {}: {}", id, from_peer, s);
+                ReceiveOutcome::Handled
             }
-            _=> {
-                warn!("Received message {} from peer {}: but we don't support it here", id, from_peer);
-            }
+            _=> ReceiveOutcome::Unhandled,
         }
     }
 
-    fn receive_must_ack(&mut self, id: MessageId, from_peer: PeerId, message: &Self::Message) -> Self::Message {
-        match message {
-            MyMessage::String(s) => {
-                info!("Received message {} from peer {}: {}", id, from_peer, s);
-            }
-            _=> {
-                warn!("Received message {} from peer {}: but weren't expecting this type of message here", id, from_peer);
-            }
+    fn ack_response(&mut self, ctx: ReceiveContext, _message: &Self::Message, outcome: ReceiveOutcome) -> AckResult<Self::Message> {
+        if outcome == ReceiveOutcome::Unhandled {
+            warn!("Received message {} from peer {}: but weren't expecting this type of message here", ctx.id, ctx.from_peer);
         }
 
         // Just respond with a "Something" for all messages. This should trigger the ack callback on the other peer.
-        Self::Message::Something
+        // Without this method, peers would get an empty ack instead.
+        AckResult::Ack(Some(Self::Message::Something))
     }
 
     fn tick(&mut self, _delta: Duration) {
@@ -59,19 +54,26 @@ impl TApp<User> for App {
     }
 
     fn post_user_connected(&mut self, peer_id: PeerId) {
-        info!("User connected {}... sending them a hello that expects an ack.", peer_id);
-        self.message_queue.enqueue(Message::new(
-            MyMessage::String("Hello!".to_string())
-        )
+        if let Some(user) = self.users.get(&peer_id) {
+            info!("User connected {}... sending them a hello that expects an ack.", user.peer_id);
+        }
+        let hello = Message::new(MyMessage::String("Hello!".to_string()))
             .to_peer(peer_id)
             .with_ack_handler(|_app, id, from_peer, message| {
                 info!("Received ack for message {} from peer {} {:?}", id, from_peer, message);
-            })
-        );
+            });
+
+        if let Err(e) = self.message_queue.enqueue(hello) {
+            warn!("Couldn't send hello to {}: {}", peer_id, e);
+        }
     }
 
-    fn post_user_disconnected(&mut self, peer_id: PeerId) {
+    fn post_user_disconnected(&mut self, peer_id: PeerId, reason: DisconnectReason) {
+        info!("User disconnected {} ({:?})", peer_id, reason);
+    }
 
+    fn on_unhandled(&mut self, id: MessageId, from_peer: PeerId, _message: &Self::Message) {
+        warn!("Received message {} from peer {}: but we don't support it here", id, from_peer);
     }
 }
 
@@ -93,11 +95,6 @@ async fn main() {
             .add_directive(tracing::Level::INFO.into()))
         .init();
 
-    let (socket, loop_fut) = WebRtcSocket::new_reliable("ws://localhost:3536/");
-
-    let loop_fut = loop_fut.fuse();
-    futures::pin_mut!(loop_fut);
-
     let timeout = Delay::new(Duration::from_millis(100));
     futures::pin_mut!(timeout);
 
@@ -106,13 +103,26 @@ async fn main() {
         message_queue: MessageQueue::<User, App, MyMessage>::new(),
     };
 
-    let mut network = NetworkManager::new(socket, app);
+    // The manager stops the loop when it's closed or dropped.
+    let (mut network, loop_fut): (NetworkManager<User, App, MyMessage>, _) = NetworkManagerBuilder::new()
+        .connect("ws://localhost:3536/", app);
+    let loop_fut = loop_fut.fuse();
+    futures::pin_mut!(loop_fut);
 
     let delta = Duration::from_millis(16);
     
     loop {
-        let delta = delta.clone();
-        network.tick(delta.clone());
+        match network.tick(delta) {
+            Ok(report) => {
+                for error in report.errors {
+                    warn!("{error}");
+                }
+            }
+            Err(error) => {
+                warn!("Stopping: {error}");
+                break;
+            }
+        }
         select! {
             // Run this loop periodically
             _ = (&mut timeout).fuse() => {
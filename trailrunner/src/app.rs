@@ -2,6 +2,78 @@ use std::time::Duration;
 use matchbox_socket::PeerId;
 use crate::prelude::*;
 
+/// What `TApp::receive` did with a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReceiveOutcome {
+    Handled,
+    /// The application doesn't handle this kind of message.
+    Unhandled,
+}
+
+/// How a peer answered a message that must be acked, returned from `TApp::ack_response` and handed to the
+/// sender's ack handler.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum AckResult<M> {
+    /// Accepted, with a response for the sender, or `None` for an empty ack.
+    Ack(Option<M>),
+    /// Declined, with why.
+    Nack(String),
+}
+
+impl<M> AckResult<M> {
+    /// The response the message was acked with, if it was accepted with one.
+    pub fn response(&self) -> Option<&M> {
+        match self {
+            AckResult::Ack(response) => response.as_ref(),
+            AckResult::Nack(_) => None,
+        }
+    }
+
+    pub fn is_nack(&self) -> bool {
+        matches!(self, AckResult::Nack(_))
+    }
+}
+
+/// Why a user was removed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// matchbox reported the connection to the peer as gone.
+    Disconnected,
+    /// We kicked them with `NetworkManager::kick`.
+    Kicked,
+    /// They told us they were leaving, by closing their `NetworkManager` or kicking us.
+    Left,
+    /// We didn't hear from them within the user timeout.
+    TimedOut,
+}
+
+/// Everything known about a received message besides its contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReceiveContext {
+    pub id: MessageId,
+    pub from_peer: PeerId,
+    /// The channel the message arrived on.
+    pub channel: usize,
+    /// The correlation id the sender attached with `Message::with_trace_id`, if any.
+    pub trace_id: Option<u128>,
+    /// Who first sent the message, if `from_peer` only relayed it to us.
+    pub origin: Option<Origin>,
+}
+
+impl ReceiveContext {
+    /// The peer the message came from originally, looking through relays.
+    pub fn original_sender(&self) -> PeerId {
+        self.origin.map_or(self.from_peer, |origin| origin.peer)
+    }
+}
+
+/// The peer that first sent a relayed message, and the id they gave it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct Origin {
+    pub peer: PeerId,
+    pub id: MessageId,
+}
+
 /// The trait that the application must implement to be used with the NetworkManager.
 ///
 /// For example:
@@ -30,15 +102,15 @@ use crate::prelude::*;
 ///         &mut self.message_queue
 ///     }
 ///
-///     fn receive(&mut self, id: MessageId, from_peer: PeerId, message: &Self::Message) {
+///     fn receive(&mut self, id: MessageId, from_peer: PeerId, message: &Self::Message) -> ReceiveOutcome {
 ///         info!("Received message {} from peer {}: {}", id, from_peer, message.data);
+///         ReceiveOutcome::Handled
 ///     }
 ///
-///     fn receive_must_ack(&mut self, id: MessageId, from_peer: PeerId, message: &Self::Message) -> Self::Message {
-///         info!("Received message {} from peer {}: {}", id, from_peer, message.data.clone());
-///         Self::Message {
+///     fn ack_response(&mut self, ctx: ReceiveContext, message: &Self::Message, outcome: ReceiveOutcome) -> AckResult<Self::Message> {
+///         AckResult::Ack(Some(Self::Message {
 ///             data: "Ok!".to_string(),
-///         }
+///         }))
 ///     }
 ///
 ///     fn tick(&mut self, delta: Duration) {
@@ -48,12 +120,12 @@ use crate::prelude::*;
 ///
 /// #[derive(Debug, Clone)]
 /// pub struct User {
-///     name: String,
+///     peer_id: PeerId,
 /// }
 ///
 /// impl TUser for User {
-///     fn new(name: String) -> Self {
-///         Self { name }
+///     fn new(peer_id: PeerId) -> Self {
+///         Self { peer_id }
 ///     }
 /// }
 ///
@@ -72,18 +144,147 @@ pub trait TApp<U: TUser> {
     fn message_queue(&mut self) -> &mut MessageQueue<U, Self::Application, Self::Message>;
 
     /// Called when a message is received. The id is the id of the message, from_peer is the peer that sent the message, and message is the message itself.
-    fn receive(&mut self, id: MessageId, from_peer: PeerId, message: &Self::Message);
-
-    /// Called when a message is received that must be acknowledged. The id is the id of the message, from_peer is the peer that sent the message, and message is the message itself. The return value is the message that will be sent back to the sender as an acknowledgment.
-    fn receive_must_ack(&mut self, id: MessageId, from_peer: PeerId, message: &Self::Message) -> Self::Message;
+    /// Return `ReceiveOutcome::Unhandled` for messages you don't handle, so they are counted and passed on to `on_unhandled`.
+    fn receive(&mut self, id: MessageId, from_peer: PeerId, message: &Self::Message) -> ReceiveOutcome;
 
-    /// Called every frame. The delta is the duration of time passed since the last frame.
+    /// Called every frame. The delta is the duration of time passed since the last frame, or the fixed step set
+    /// with `NetworkManager::with_fixed_step`, in which case it may be called several times a frame or not at all.
     fn tick(&mut self, delta: Duration);
 
     // No need to implement these
 
+    /// Called for a received message that must be acknowledged, right after it was delivered like any other
+    /// message, with what receiving it returned. The return value is sent back to the sender as the
+    /// acknowledgment. Return `AckResult::Nack` to decline the message instead, e.g. a trade the user won't
+    /// accept. The default implementation sends back an empty ack, implement this when the sender expects an
+    /// actual response.
+    fn ack_response(
+        &mut self,
+        _ctx: ReceiveContext,
+        _message: &Self::Message,
+        _outcome: ReceiveOutcome,
+    ) -> AckResult<Self::Message> {
+        AckResult::Ack(None)
+    }
+
+    /// Called for every connection change matchbox reports, before the user hooks. With
+    /// `NetworkManager::with_manual_users` it's the only hook that fires for it, and adding and removing users
+    /// is up to you.
+    fn on_peer_state_changed(&mut self, _peer_id: PeerId, _state: PeerState) {}
+
+    /// Called once per tick when the number of connected peers changed, after the connect and disconnect hooks
+    /// for it ran. Kicked and rejected peers aren't counted. Handy for lobby logic like starting a match once
+    /// enough players are in.
+    fn on_peer_count_changed(&mut self, _old: usize, _new: usize) {}
+
+    /// Called the moment a peer connects, before anything else happens for them and before they have a user.
+    /// The order for a new peer is `on_peer_connecting`, `should_accept`, `create_user`, then
+    /// `post_user_connected`.
+    fn on_peer_connecting(&mut self, _peer_id: PeerId) {}
+
+    /// Whether to let a peer that is connecting in. Rejected peers never get a user, and everything they send
+    /// is ignored. matchbox can't refuse the connection itself, so it stays open until they leave.
+    fn should_accept(&mut self, _peer_id: PeerId) -> bool {
+        true
+    }
+
+    /// Called when messages were held for a peer that didn't finish connecting within the timeout set with
+    /// `NetworkManager::with_connect_timeout`. The messages have been dropped.
+    fn on_connect_failed(&mut self, _peer_id: PeerId) {}
+
+    /// Creates the user for a peer that just connected, before `post_user_connected`. Override this to set
+    /// users up with more than their peer id, e.g. defaults from the app's config or an assigned team.
+    fn create_user(&mut self, peer_id: PeerId) -> U {
+        U::new(peer_id)
+    }
+
+    /// Creates the user for a peer that sent a hello with `meta`, see `NetworkManager::with_hello`, in place
+    /// of `create_user`.
+    fn create_user_with_meta(&mut self, peer_id: PeerId, meta: &[u8]) -> U {
+        U::new_with_meta(peer_id, meta)
+    }
+
     fn post_user_connected(&mut self, _peer_id: PeerId) {}
-    fn post_user_disconnected(&mut self, _peer_id: PeerId) {}
+
+    /// Called when we haven't heard from a peer within the timeout set with `NetworkManager::with_user_timeout`
+    /// or `with_heartbeat`, right before they're removed and `post_user_disconnected` fires with
+    /// `DisconnectReason::TimedOut`.
+    fn on_peer_timeout(&mut self, _peer_id: PeerId) {}
+
+    /// Called when a peer that left within the window set with `NetworkManager::with_rejoin_window` connects
+    /// again, after `post_user_connected`, e.g. when a mesh split by a signaling hiccup comes back together.
+    /// Their state may have diverged from ours in the meantime, so this is the place to resync it.
+    ///
+    /// The signaling server hands out a new peer id on every connection, so peers are recognised by the key
+    /// set with `NetworkManager::with_reconnect_dedup`, which their new user needs to have from the start, e.g.
+    /// from `TApp::create_user_with_meta`. `peer_id` is their new id then. Without a key this only fires for a
+    /// peer that comes back under the same peer id, which is rare.
+    fn on_peer_rejoined(&mut self, _peer_id: PeerId, _was_gone_for: Duration) {}
+    fn post_user_disconnected(&mut self, _peer_id: PeerId, _reason: DisconnectReason) {}
+
+    /// Called for every received message that doesn't need an ack, with everything known about it. The default
+    /// implementation forwards to `receive`, implement this instead when you need e.g. the trace id.
+    fn receive_with_context(&mut self, ctx: ReceiveContext, message: &Self::Message) -> ReceiveOutcome {
+        self.receive(ctx.id, ctx.from_peer, message)
+    }
+
+    /// Called instead of `receive` when user dispatch is turned on with `NetworkManager::with_user_dispatch`
+    /// and the sender has a user, saving you from looking them up while other fields of the app are borrowed.
+    ///
+    /// While this runs the user is taken out of the `UserList`, so looking them up there finds nothing. They
+    /// are put back afterwards. The default implementation forwards to `receive_with_context`.
+    fn receive_from_user(&mut self, ctx: ReceiveContext, _user: &mut U, message: &Self::Message) -> ReceiveOutcome {
+        self.receive_with_context(ctx, message)
+    }
+
+    /// Called when `receive` returned `ReceiveOutcome::Unhandled`, a sign that peers are sending messages we don't understand.
+    fn on_unhandled(&mut self, _id: MessageId, _from_peer: PeerId, _message: &Self::Message) {}
+
+    /// Called for each peer that never acked a message sent `with_ack_timeout`, once its retries ran out and
+    /// it was given up on.
+    fn on_ack_timeout(&mut self, _id: MessageId, _peer_id: PeerId) {}
+
+    /// Called when a message was given up on because `peer_id`, the last peer it was waiting on, disconnected
+    /// before acking it. Its ack handler won't be called.
+    fn on_ack_abandoned(&mut self, _id: MessageId, _peer_id: PeerId) {}
+
+    /// Called when the peers a message waits on acks from change while it's waiting: `removed` disconnected
+    /// before acking, `added` connected and got the broadcast under `LateJoinPolicy::Include`.
+    fn on_ack_target_changed(&mut self, _id: MessageId, _added: &[PeerId], _removed: &[PeerId]) {}
+
+    /// Called when an ack arrives for a message we're not waiting on (anymore), e.g. because it was dropped by
+    /// `close`, or the peer was kicked first. Acks arriving here regularly can mean timeouts are too tight.
+    fn on_orphan_ack(&mut self, _id: MessageId, _from_peer: PeerId, _data: &AckResult<Self::Message>) {}
+
+    /// Called when a packet from `from_peer` was dropped because it wasn't signed or its signature didn't
+    /// check out with the signer set with `NetworkManager::with_signer`.
+    fn on_signature_invalid(&mut self, _from_peer: PeerId) {}
+
+    /// Called with the serialized bytes of every message about to be sent to `to_peer`, for logging packets
+    /// or tracking down wire format mismatches. These are the bytes before signing and simulated network
+    /// conditions are applied, acks and stream chunks aren't included.
+    fn on_wire_out(&mut self, _id: MessageId, _to_peer: PeerId, _bytes: &[u8]) {}
+
+    /// Called with every packet received from `from_peer` exactly as it arrived, before it's deserialized or
+    /// its signature checked, acks included. Not called for peers we're ignoring.
+    fn on_wire_in(&mut self, _from_peer: PeerId, _bytes: &[u8]) {}
+
+    /// Called with each piece of a stream a peer sent with `NetworkManager::send_stream`, in order.
+    fn on_stream_chunk(&mut self, _from_peer: PeerId, _stream: StreamId, _data: &[u8]) {}
+
+    /// Called after the last chunk of a stream arrived.
+    fn on_stream_complete(&mut self, _from_peer: PeerId, _stream: StreamId) {}
+
+    /// Called when the sender of a stream couldn't read all of it, the chunks so far are all there is.
+    fn on_stream_aborted(&mut self, _from_peer: PeerId, _stream: StreamId) {}
+
+    /// Called once per tick with every ack that arrived during it, when turned on with
+    /// `NetworkManager::with_delivery_receipts`. Not called for ticks without any.
+    fn on_receipts(&mut self, _receipts: &[DeliveryReceipt]) {}
+
+    /// Called once at the very end of `NetworkManager::tick`, after everything was received and sent and
+    /// `tick` ran. A place for end of frame networking work like flushing stats or swapping buffers.
+    fn post_tick(&mut self, _delta: Duration) {}
 
     fn get_users_mut(&mut self) -> &mut UserList<U> {
         self.users()
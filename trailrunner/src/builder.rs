@@ -0,0 +1,220 @@
+use std::time::Duration;
+use matchbox_socket::{ChannelConfig, RtcIceServerConfig, WebRtcSocket, WebRtcSocketBuilder};
+use crate::prelude::*;
+
+/// Sets up a `NetworkManager` together with the socket it runs on, so the manager knows how it connected, or
+/// on a socket built elsewhere with `build`. Anything not set keeps the manager's default.
+///
+/// ```no_run
+/// # use trailrunner::prelude::*;
+/// # fn run<U: TUser, T: TApp<U, Application = T, Message = M>, M: TSerializableMessage>(app: T) {
+/// let (network, message_loop): (NetworkManager<U, T, M>, _) = NetworkManagerBuilder::new()
+///     .add_channel(ChannelConfig::reliable())
+///     .add_channel(ChannelConfig::unreliable())
+///     .connect("ws://localhost:3536/my_room", app);
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct NetworkManagerBuilder {
+    channels: Vec<ChannelConfig>,
+    /// `None` keeps matchbox's default STUN server.
+    ice_server: Option<RtcIceServerConfig>,
+    /// The username and credential for the TURN servers in `ice_server`, kept apart so they never end up on
+    /// matchbox's default server.
+    turn_credentials: Option<(String, String)>,
+    ack_timeout: Option<Duration>,
+    max_retries: Option<u32>,
+    dedup_window: Option<usize>,
+    compression: Compression,
+    orderings: Vec<(usize, ChannelOrdering)>,
+}
+
+impl NetworkManagerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a channel to the socket, in order. Without any, the socket gets a single reliable channel.
+    pub fn add_channel(mut self, config: ChannelConfig) -> Self {
+        self.channels.push(config);
+        self
+    }
+
+    /// Sets the whole ICE server configuration the socket uses to get through NAT, replacing matchbox's
+    /// default public STUN server.
+    pub fn ice_server(mut self, ice_server: RtcIceServerConfig) -> Self {
+        self.ice_server = Some(ice_server);
+        self
+    }
+
+    /// The STUN and TURN server urls to use instead of matchbox's default STUN server, e.g.
+    /// `["stun:stun.example.com:3478", "turn:turn.example.com:3478"]`. Credentials set with `ice_server` are
+    /// kept.
+    pub fn ice_servers(mut self, urls: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        let urls = urls.into_iter().map(Into::into).collect();
+        match self.ice_server.as_mut() {
+            Some(ice_server) => ice_server.urls = urls,
+            None => self.ice_server = Some(RtcIceServerConfig { urls, username: None, credential: None }),
+        }
+        self
+    }
+
+    /// The username and credential for the TURN servers set with `ice_servers` or `ice_server`, needed to
+    /// relay through them in networks too restrictive for a direct connection. They replace the credentials
+    /// in `ice_server`, and are only used when it has a `turn:` or `turns:` url, so they're never sent to
+    /// matchbox's default STUN server.
+    pub fn turn_credentials(mut self, username: impl Into<String>, credential: impl Into<String>) -> Self {
+        self.turn_credentials = Some((username.into(), credential.into()));
+        self
+    }
+
+    /// Sends messages waiting on acks again when their acks don't show up within `timeout`, see
+    /// `NetworkManager::with_ack_timeout`.
+    pub fn ack_timeout(mut self, timeout: Duration) -> Self {
+        self.ack_timeout = Some(timeout);
+        self
+    }
+
+    /// How many times messages are sent again before giving up on their acks, see
+    /// `NetworkManager::with_ack_retries`.
+    pub fn max_retries(mut self, retries: u32) -> Self {
+        self.max_retries = Some(retries);
+        self
+    }
+
+    /// How many recent message ids are remembered per peer to drop repeats, see
+    /// `NetworkManager::with_dedup_window`.
+    pub fn dedup_window(mut self, capacity: usize) -> Self {
+        self.dedup_window = Some(capacity);
+        self
+    }
+
+    /// The order messages received on `channel` are handled in, e.g. `ChannelOrdering::Sequenced` to have them
+    /// handled in the order they were sent, see `NetworkManager::with_channel_ordering`.
+    pub fn channel_ordering(mut self, channel: usize, ordering: ChannelOrdering) -> Self {
+        self.orderings.push((channel, ordering));
+        self
+    }
+
+    /// How packets are compressed, see `NetworkManager::with_compression`.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Creates the manager on a socket that's already built. The socket has its own channels and ICE
+    /// servers, those set here only apply to `connect`.
+    ///
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// # use trailrunner::prelude::*;
+    /// # fn run<U: TUser, T: TApp<U, Application = T, Message = M>, M: TSerializableMessage>(app: T) {
+    /// let (socket, message_loop) = WebRtcSocket::new_reliable("ws://localhost:3536/my_room");
+    /// let network: NetworkManager<U, T, M> = NetworkManagerBuilder::new()
+    ///     .ack_timeout(Duration::from_secs(2))
+    ///     .max_retries(3)
+    ///     .build(socket, app);
+    /// # }
+    /// ```
+    pub fn build<U, T, M>(self, socket: WebRtcSocket, app: T) -> NetworkManager<U, T, M>
+    where
+        T: TApp<U, Application = T, Message = M>,
+        U: TUser,
+        M: TSerializableMessage
+    {
+        self.configure(NetworkManager::new(socket, app))
+    }
+
+    /// Creates the socket for the room at `room_url` and the manager running on it. The returned loop has to
+    /// be driven for anything to be sent or received, it stops once the manager is closed or dropped.
+    pub fn connect<U, T, M>(mut self, room_url: impl Into<String>, app: T) -> (NetworkManager<U, T, M>, ManagedMessageLoop)
+    where
+        T: TApp<U, Application = T, Message = M>,
+        U: TUser,
+        M: TSerializableMessage
+    {
+        let room_url = room_url.into();
+        let channels = match self.channels.is_empty() {
+            true => vec![ChannelConfig::reliable()],
+            false => std::mem::take(&mut self.channels),
+        };
+
+        let mut socket_builder = WebRtcSocketBuilder::new(room_url.clone());
+        if let Some(ice_server) = self.ice_server_config() {
+            socket_builder = socket_builder.ice_server(ice_server);
+        }
+        for config in channels {
+            socket_builder = socket_builder.add_channel(config);
+        }
+        let (socket, message_loop) = socket_builder.build();
+
+        let mut network = self.configure(NetworkManager::new(socket, app));
+        network.set_room_url(room_url);
+        let message_loop = network.attach_message_loop(message_loop);
+        (network, message_loop)
+    }
+
+    /// The ICE servers `connect` uses, with the TURN credentials if there are TURN servers to use them with.
+    fn ice_server_config(&self) -> Option<RtcIceServerConfig> {
+        let mut ice_server = self.ice_server.clone();
+        let Some((username, credential)) = self.turn_credentials.clone() else {
+            return ice_server;
+        };
+        let is_turn = |url: &String| url.starts_with("turn:") || url.starts_with("turns:");
+        match ice_server.as_mut().filter(|ice_server| ice_server.urls.iter().any(is_turn)) {
+            Some(ice_server) => {
+                ice_server.username = Some(username);
+                ice_server.credential = Some(credential);
+            }
+            None => log::warn!("Ignoring the TURN credentials, no TURN server was set to use them with"),
+        }
+        ice_server
+    }
+
+    fn configure<U, T, M>(&self, mut network: NetworkManager<U, T, M>) -> NetworkManager<U, T, M>
+    where
+        T: TApp<U, Application = T, Message = M>,
+        U: TUser,
+        M: TSerializableMessage
+    {
+        if let Some(timeout) = self.ack_timeout {
+            network = network.with_ack_timeout(timeout);
+        }
+        if let Some(retries) = self.max_retries {
+            network = network.with_ack_retries(retries);
+        }
+        if let Some(capacity) = self.dedup_window {
+            network = network.with_dedup_window(capacity);
+        }
+        for (channel, ordering) in self.orderings.iter().copied() {
+            network = network.with_channel_ordering(channel, ordering);
+        }
+        network.with_compression(self.compression)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_turn_credentials_to_turn_servers() {
+        let builder = NetworkManagerBuilder::new()
+            .turn_credentials("user", "secret")
+            .ice_servers(["stun:stun.example.com:3478", "turn:turn.example.com:3478"]);
+        let ice_server = builder.ice_server_config().unwrap();
+        assert_eq!(ice_server.username.as_deref(), Some("user"));
+        assert_eq!(ice_server.credential.as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn never_sends_turn_credentials_to_other_servers() {
+        let builder = NetworkManagerBuilder::new().turn_credentials("user", "secret");
+        assert!(builder.ice_server_config().is_none());
+
+        let builder = builder.ice_servers(["stun:stun.example.com:3478"]);
+        let ice_server = builder.ice_server_config().unwrap();
+        assert_eq!(ice_server.username, None);
+        assert_eq!(ice_server.credential, None);
+    }
+}
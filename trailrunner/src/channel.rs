@@ -0,0 +1,85 @@
+use matchbox_socket::{ChannelConfig, ChannelError, WebRtcSocket};
+
+/// What delivery guarantee a message needs, see `Message::reliability`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reliability {
+    /// Retransmitted until it arrives, e.g. inventory changes.
+    Reliable,
+    /// Sent once and may be lost, for data that's soon outdated anyway, e.g. positions.
+    Unreliable,
+}
+
+/// The order messages received on a channel are handled in, see `NetworkManager::with_channel_ordering`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChannelOrdering {
+    /// As they arrive, which on an unordered channel, or for messages sent again after a lost ack, isn't
+    /// always the order they were sent in.
+    #[default]
+    Arrival,
+    /// In the order each sender sent them. This is the order packets went out, not of message ids, so a
+    /// message sent again by `Message::with_ack_timeout` is handled where its repeat falls.
+    ///
+    /// On a reliable channel, messages that arrive early are held back until those sent before them arrived,
+    /// or until the sequence timeout passes without them. On an unreliable channel nothing is held back,
+    /// messages older than one already handled are dropped instead, so only the newest state is acted on.
+    Sequenced,
+}
+
+/// Describes one of the data channels the socket was built with.
+///
+/// The `NetworkManager` reads these from the socket when it is created, so library code can pick an
+/// appropriate channel for a class of message without hardcoding indices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelInfo {
+    /// The index used to address this channel on the socket.
+    pub index: usize,
+    /// Whether packets on this channel are retransmitted until they arrive.
+    pub reliable: bool,
+    /// Whether packets on this channel arrive in the order they were sent.
+    pub ordered: bool,
+    /// The maximum number of retransmits before a packet is given up on, `None` meaning unlimited.
+    pub max_retransmits: Option<u16>,
+}
+
+impl ChannelInfo {
+    pub(crate) fn from_config(index: usize, config: &ChannelConfig) -> Self {
+        Self {
+            index,
+            reliable: config.max_retransmits.is_none(),
+            ordered: config.ordered,
+            max_retransmits: config.max_retransmits,
+        }
+    }
+
+    /// Finds a reliable channel with the given ordering, falling back to any reliable channel.
+    pub fn find_reliable(channels: &[ChannelInfo], ordered: bool) -> Option<usize> {
+        let mut reliable = channels.iter().filter(|channel| channel.reliable);
+        reliable.clone()
+            .find(|channel| channel.ordered == ordered)
+            .or_else(|| reliable.next())
+            .map(|channel| channel.index)
+    }
+
+    /// Finds an unreliable channel, preferring an unordered one since waiting on order defeats the point.
+    pub fn find_unreliable(channels: &[ChannelInfo]) -> Option<usize> {
+        let mut unreliable = channels.iter().filter(|channel| !channel.reliable);
+        unreliable.clone()
+            .find(|channel| !channel.ordered)
+            .or_else(|| unreliable.next())
+            .map(|channel| channel.index)
+    }
+
+    /// Reads the configuration of every channel still owned by the socket.
+    pub(crate) fn collect(socket: &WebRtcSocket) -> Vec<ChannelInfo> {
+        let mut channels = Vec::new();
+        for index in 0.. {
+            match socket.get_channel(index) {
+                Ok(channel) => channels.push(Self::from_config(index, channel.config())),
+                // Channels taken out of the socket by the user are no longer ours to route through
+                Err(ChannelError::Taken) => continue,
+                Err(_) => break,
+            }
+        }
+        channels
+    }
+}
@@ -0,0 +1,235 @@
+use std::borrow::Cow;
+use matchbox_socket::Packet;
+
+/// How packets are compressed before they're sent, see `NetworkManager::with_compression`.
+///
+/// Every packet starts with a byte saying how it was compressed, so peers using different settings still
+/// understand each other, as long as they were built with the feature for the codec they receive.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// Packets are sent as they are.
+    #[default]
+    None,
+    /// Packets are compressed with the LZ4 block format, fast enough to run on every packet. Needs the `lz4`
+    /// feature.
+    #[cfg(feature = "lz4")]
+    Lz4,
+    /// Packets are compressed with Zstd, which shrinks them further than LZ4 but costs more time. Needs the
+    /// `zstd` feature.
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+const NONE_HEADER: u8 = 0;
+const LZ4_HEADER: u8 = 1;
+const ZSTD_HEADER: u8 = 2;
+
+/// Nothing decompresses to more than this, a packet that would is dropped instead of filling memory.
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+const MAX_DECOMPRESSED_BYTES: usize = crate::fragment::DEFAULT_MAX_REASSEMBLY_BYTES;
+
+impl Compression {
+    fn header(self) -> u8 {
+        match self {
+            Compression::None => NONE_HEADER,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => LZ4_HEADER,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => ZSTD_HEADER,
+        }
+    }
+
+    /// `packet` compressed, behind the header byte. Packets that don't get any smaller, like most acks, are sent
+    /// as they are.
+    pub(crate) fn compress(self, packet: &[u8]) -> Packet {
+        let compressed: Option<Vec<u8>> = match self {
+            Compression::None => None,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Some(lz4_flex::block::compress_prepend_size(packet)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Some(ruzstd::encoding::compress_to_vec(packet, ruzstd::encoding::CompressionLevel::Fastest)),
+        };
+        let (header, body) = match &compressed {
+            Some(compressed) if compressed.len() < packet.len() => (self.header(), &compressed[..]),
+            _ => (NONE_HEADER, packet),
+        };
+
+        let mut framed = Vec::with_capacity(body.len() + 1);
+        framed.push(header);
+        framed.extend_from_slice(body);
+        framed.into_boxed_slice()
+    }
+
+    /// The packet `compress` was given, from what it returned.
+    pub(crate) fn decompress(packet: &[u8]) -> Result<Cow<'_, [u8]>, &'static str> {
+        match packet.split_first() {
+            Some((&NONE_HEADER, body)) => Ok(Cow::Borrowed(body)),
+            Some((&LZ4_HEADER, body)) => decompress_lz4(body).map(Cow::Owned),
+            Some((&ZSTD_HEADER, body)) => decompress_zstd(body).map(Cow::Owned),
+            Some(_) => Err("unknown compression"),
+            None => Err("empty packet"),
+        }
+    }
+}
+
+/// An LZ4 block behind its decompressed size, as `lz4_flex::block::compress_prepend_size` writes it.
+#[cfg(feature = "lz4")]
+fn decompress_lz4(body: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let (size, block) = body.split_first_chunk::<4>().ok_or("truncated lz4 block")?;
+    let size = u32::from_le_bytes(*size) as usize;
+    // LZ4 can't shrink anything by more than 255 times, don't let a bogus size allocate more than that
+    if size > MAX_DECOMPRESSED_BYTES || size > block.len().saturating_mul(255) {
+        return Err("lz4 block size out of bounds");
+    }
+    match lz4_flex::block::decompress(block, size) {
+        Ok(decompressed) if decompressed.len() == size => Ok(decompressed),
+        Ok(_) => Err("lz4 block shorter than its size"),
+        Err(_) => Err("malformed lz4 block"),
+    }
+}
+
+#[cfg(not(feature = "lz4"))]
+fn decompress_lz4(_body: &[u8]) -> Result<Vec<u8>, &'static str> {
+    Err("lz4 compression needs the lz4 feature")
+}
+
+#[cfg(feature = "zstd")]
+fn decompress_zstd(body: &[u8]) -> Result<Vec<u8>, &'static str> {
+    use std::io::Read;
+
+    let decoder = ruzstd::decoding::StreamingDecoder::new(body).map_err(|_| "malformed zstd frame")?;
+    let mut decompressed = Vec::new();
+    decoder.take(MAX_DECOMPRESSED_BYTES as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|_| "malformed zstd frame")?;
+    match decompressed.len() > MAX_DECOMPRESSED_BYTES {
+        true => Err("zstd frame too large"),
+        false => Ok(decompressed),
+    }
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decompress_zstd(_body: &[u8]) -> Result<Vec<u8>, &'static str> {
+    Err("zstd compression needs the zstd feature")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every codec this was built with, `None` included.
+    fn codecs() -> Vec<Compression> {
+        vec![
+            Compression::None,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd,
+        ]
+    }
+
+    fn roundtrip(compression: Compression, input: &[u8]) {
+        let compressed = compression.compress(input);
+        assert_eq!(&Compression::decompress(&compressed).unwrap()[..], input, "{compression:?}");
+    }
+
+    /// Deterministic bytes drawn from an alphabet of `alphabet` symbols, the smaller the more compressible.
+    fn noise(len: usize, alphabet: u64, mut seed: u64) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                ((seed >> 33) % alphabet) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn roundtrips() {
+        for compression in codecs() {
+            roundtrip(compression, b"");
+            roundtrip(compression, b"a");
+            roundtrip(compression, &[7; 10_000]);
+            roundtrip(compression, &b"trailrunner ".repeat(500));
+            for (len, alphabet) in [(100, 2), (1000, 4), (5000, 16), (70_000, 3), (3000, 256)] {
+                roundtrip(compression, &noise(len, alphabet, len as u64));
+            }
+        }
+    }
+
+    #[test]
+    fn compresses_repetitive_packets() {
+        let input = b"trailrunner ".repeat(100);
+        for compression in codecs().into_iter().filter(|&c| c != Compression::None) {
+            let compressed = compression.compress(&input);
+            assert_eq!(compressed[0], compression.header());
+            assert!(compressed.len() < input.len() / 10, "{compression:?}");
+        }
+    }
+
+    #[test]
+    fn sends_incompressible_packets_as_they_are() {
+        let input = noise(64, 256, 7);
+        for compression in codecs() {
+            let compressed = compression.compress(&input);
+            assert_eq!(compressed[0], Compression::None.header());
+            assert_eq!(&compressed[1..], &input[..]);
+        }
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn decodes_reference_lz4_blocks() {
+        // Made with the reference `lz4 -9` and taken out of its frame
+        let block: &[u8] = &[
+            248, 12, 116, 114, 97, 105, 108, 114, 117, 110, 110, 101, 114, 32, 115, 101, 110, 100, 115, 32, 112,
+            97, 99, 107, 101, 116, 115, 44, 32, 27, 0, 62, 97, 99, 107, 26, 0, 15, 53, 0, 81, 112, 104, 101, 32,
+            101, 110, 100, 46,
+        ];
+        let expected = [b"trailrunner sends packets, trailrunner acks packets, ".repeat(3), b"the end.".to_vec()].concat();
+
+        let mut packet = vec![Compression::Lz4.header()];
+        packet.extend_from_slice(&(expected.len() as u32).to_le_bytes());
+        packet.extend_from_slice(block);
+        assert_eq!(&Compression::decompress(&packet).unwrap()[..], &expected[..]);
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        assert!(Compression::decompress(&[]).is_err());
+        assert!(Compression::decompress(&[9, 1, 2]).is_err());
+        // Compressed by a codec this wasn't built with, or not really compressed at all
+        assert_eq!(Compression::decompress(&[LZ4_HEADER, 1, 0, 0, 0, 0x10, 1]).is_ok(), cfg!(feature = "lz4"));
+        assert!(Compression::decompress(&[ZSTD_HEADER, 1, 2, 3]).is_err());
+
+        let lz4 = |body: &[u8]| [&[LZ4_HEADER][..], body].concat();
+        // Truncated size, truncated token, literals running past the end
+        assert!(Compression::decompress(&lz4(&[1, 0])).is_err());
+        assert!(Compression::decompress(&lz4(&[1, 0, 0, 0])).is_err());
+        assert!(Compression::decompress(&lz4(&[5, 0, 0, 0, 0x50, 1, 2])).is_err());
+        // A match reaching back before the start
+        assert!(Compression::decompress(&lz4(&[8, 0, 0, 0, 0x10, 1, 5, 0, 0x00])).is_err());
+        // More or less output than the size in front says
+        assert!(Compression::decompress(&lz4(&[2, 0, 0, 0, 0x30, 1, 2, 3])).is_err());
+        assert!(Compression::decompress(&lz4(&[4, 0, 0, 0, 0x30, 1, 2, 3])).is_err());
+        // A huge claimed size is refused without allocating it
+        assert!(Compression::decompress(&lz4(&[255, 255, 255, 255, 0x10, 1])).is_err());
+        // An unterminated length
+        assert!(Compression::decompress(&lz4(&[0, 1, 0, 0, 0xf0, 255, 255])).is_err());
+    }
+
+    #[test]
+    fn never_panics_on_corrupted_packets() {
+        let input = b"trailrunner ".repeat(50);
+        for compression in codecs().into_iter().filter(|&c| c != Compression::None) {
+            let compressed = compression.compress(&input);
+            for i in 1..compressed.len() {
+                for flip in [0x01, 0x10, 0x80, 0xff] {
+                    let mut corrupted = compressed.to_vec();
+                    corrupted[i] ^= flip;
+                    let _ = Compression::decompress(&corrupted);
+                    let _ = Compression::decompress(&corrupted[..i]);
+                }
+            }
+        }
+    }
+}
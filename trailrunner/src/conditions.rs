@@ -0,0 +1,184 @@
+use std::time::Duration;
+use matchbox_socket::{Packet, PeerId};
+use crate::channel::ChannelInfo;
+
+/// Artificially degrades the connection so apps can test how they cope with a bad network.
+///
+/// Applied to every packet the `NetworkManager` sends and receives, within what the channel it goes over
+/// guarantees: packets on reliable channels are never lost and packets on ordered channels never overtake
+/// each other. Given the same seed and the same sequence of ticks, the same packets are dropped and delayed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NetworkConditions {
+    /// Delay added to every packet.
+    pub added_latency: Duration,
+    /// Up to this much extra delay is added on top of `added_latency`, picked at random per packet. On ordered
+    /// channels a packet is held at least until the one sent before it comes out.
+    pub jitter: Duration,
+    /// The chance a packet on an unreliable channel is dropped, between `0.0` and `1.0`.
+    pub loss_rate: f32,
+    /// Seeds the random choices so runs can be reproduced.
+    pub seed: u64,
+}
+
+impl Default for NetworkConditions {
+    fn default() -> Self {
+        Self {
+            added_latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            loss_rate: 0.0,
+            seed: 0,
+        }
+    }
+}
+
+/// A small seedable random number generator (splitmix64), good enough for simulation and sampling.
+#[derive(Debug, Clone)]
+pub(crate) struct SimRng(u64);
+
+impl SimRng {
+    pub(crate) fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0.0..1.0`.
+    pub(crate) fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+pub(crate) struct DelayedPacket {
+    release_at: Duration,
+    pub(crate) channel: usize,
+    pub(crate) peer: PeerId,
+    pub(crate) packet: Packet,
+}
+
+/// Holds packets back (or drops them) according to `NetworkConditions`.
+pub(crate) struct ConditionedLink {
+    conditions: NetworkConditions,
+    rng: SimRng,
+    outgoing: Vec<DelayedPacket>,
+    incoming: Vec<DelayedPacket>,
+}
+
+impl ConditionedLink {
+    pub(crate) fn new(conditions: NetworkConditions) -> Self {
+        Self {
+            rng: SimRng::new(conditions.seed),
+            conditions,
+            outgoing: Vec::new(),
+            incoming: Vec::new(),
+        }
+    }
+
+    /// Queues a packet sent or received now over `channel`, unless it's lost.
+    fn push(&mut self, outgoing: bool, now: Duration, channel: &ChannelInfo, peer: PeerId, packet: Packet) {
+        // Both are always drawn so a run only depends on the seed and the packets, not the channels
+        let lost = self.rng.next_f64() < self.conditions.loss_rate as f64;
+        let jitter = self.conditions.jitter.mul_f64(self.rng.next_f64());
+        if lost && !channel.reliable {
+            return;
+        }
+
+        let packets = match outgoing {
+            true => &mut self.outgoing,
+            false => &mut self.incoming,
+        };
+        let mut release_at = now + self.conditions.added_latency + jitter;
+        if channel.ordered {
+            let queued = packets.iter().filter(|delayed| delayed.channel == channel.index && delayed.peer == peer);
+            release_at = queued.map(|delayed| delayed.release_at).fold(release_at, Duration::max);
+        }
+        packets.push(DelayedPacket { release_at, channel: channel.index, peer, packet });
+    }
+
+    pub(crate) fn push_outgoing(&mut self, now: Duration, channel: &ChannelInfo, peer: PeerId, packet: Packet) {
+        self.push(true, now, channel, peer, packet);
+    }
+
+    pub(crate) fn push_incoming(&mut self, now: Duration, channel: &ChannelInfo, peer: PeerId, packet: Packet) {
+        self.push(false, now, channel, peer, packet);
+    }
+
+    pub(crate) fn take_due_outgoing(&mut self, now: Duration) -> Vec<DelayedPacket> {
+        Self::take_due(&mut self.outgoing, now, None)
+    }
+
+    pub(crate) fn take_due_incoming(&mut self, now: Duration, channel: usize) -> Vec<DelayedPacket> {
+        Self::take_due(&mut self.incoming, now, Some(channel))
+    }
+
+    fn take_due(packets: &mut Vec<DelayedPacket>, now: Duration, channel: Option<usize>) -> Vec<DelayedPacket> {
+        // Stable sort keeps packets released at the same time in the order they were sent
+        packets.sort_by_key(|delayed| delayed.release_at);
+        let (due, held) = packets.drain(..).partition(|delayed| {
+            delayed.release_at <= now && channel.is_none_or(|channel| delayed.channel == channel)
+        });
+        *packets = held;
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+    use super::*;
+
+    const PEER: PeerId = PeerId(Uuid::from_u128(1));
+
+    fn channel(reliable: bool, ordered: bool) -> ChannelInfo {
+        ChannelInfo { index: 0, reliable, ordered, max_retransmits: (!reliable).then_some(0) }
+    }
+
+    fn link(loss_rate: f32, jitter: Duration) -> ConditionedLink {
+        ConditionedLink::new(NetworkConditions {
+            added_latency: Duration::from_millis(50),
+            jitter,
+            loss_rate,
+            seed: 7,
+        })
+    }
+
+    /// Sends packets `0..count` over `channel` one millisecond apart and returns the ones that came out, in
+    /// the order they did.
+    fn send(link: &mut ConditionedLink, channel: &ChannelInfo, count: u8) -> Vec<u8> {
+        for i in 0..count {
+            link.push_outgoing(Duration::from_millis(i as u64), channel, PEER, Box::new([i]));
+        }
+        link.take_due_outgoing(Duration::from_secs(60)).into_iter().map(|delayed| delayed.packet[0]).collect()
+    }
+
+    #[test]
+    fn only_loses_packets_on_unreliable_channels() {
+        assert_eq!(send(&mut link(1.0, Duration::ZERO), &channel(true, true), 10), (0..10).collect::<Vec<_>>());
+        assert!(send(&mut link(1.0, Duration::ZERO), &channel(false, false), 10).is_empty());
+        assert!(send(&mut link(0.5, Duration::ZERO), &channel(false, false), 100).len() < 100);
+    }
+
+    #[test]
+    fn only_reorders_packets_on_unordered_channels() {
+        let jitter = Duration::from_millis(200);
+        let sent: Vec<u8> = (0..100).collect();
+        assert_eq!(send(&mut link(0.0, jitter), &channel(true, true), 100), sent);
+        assert_eq!(send(&mut link(0.0, jitter), &channel(false, true), 100), sent);
+        assert_ne!(send(&mut link(0.0, jitter), &channel(true, false), 100), sent);
+    }
+
+    #[test]
+    fn delays_every_channel() {
+        for (reliable, ordered) in [(true, true), (true, false), (false, true), (false, false)] {
+            let mut link = link(0.0, Duration::ZERO);
+            link.push_outgoing(Duration::ZERO, &channel(reliable, ordered), PEER, Box::new([0]));
+            assert!(link.take_due_outgoing(Duration::from_millis(49)).is_empty());
+            assert_eq!(link.take_due_outgoing(Duration::from_millis(50)).len(), 1);
+        }
+    }
+}
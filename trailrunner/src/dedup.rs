@@ -0,0 +1,64 @@
+use std::collections::{HashMap, VecDeque};
+use matchbox_socket::PeerId;
+use crate::prelude::*;
+
+/// How many message ids are remembered per peer for spotting duplicates.
+pub const DEFAULT_DEDUP_WINDOW: usize = 256;
+
+/// Remembers the ids of the last few messages received from each peer, and what we acked them with, so
+/// duplicates can be recognised and answered with the same ack.
+pub(crate) struct DedupWindow<M> {
+    capacity: usize,
+    seen: HashMap<PeerId, VecDeque<(MessageId, Option<M>)>>,
+    /// Ids at or below these were received over an earlier connection of the same sender, and count as seen.
+    floors: HashMap<PeerId, MessageId>,
+}
+
+impl<M> DedupWindow<M> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            seen: HashMap::new(),
+            floors: HashMap::new(),
+        }
+    }
+
+    /// If message `id` from `peer` was seen before, what we acked it with, if anything.
+    pub(crate) fn get(&self, peer: &PeerId, id: MessageId) -> Option<Option<&M>> {
+        let recent = self.seen.get(peer)
+            .and_then(|seen| seen.iter().find(|(seen, _)| *seen == id))
+            .map(|(_, response)| response.as_ref());
+        match self.floors.get(peer) {
+            Some(floor) if recent.is_none() && id <= *floor => Some(None),
+            _ => recent,
+        }
+    }
+
+    /// The highest message id seen from `peer`, including over an earlier connection carried over to them.
+    pub(crate) fn high_water(&self, peer: &PeerId) -> Option<MessageId> {
+        let recent = self.seen.get(peer).and_then(|seen| seen.iter().map(|(id, _)| *id).max());
+        recent.max(self.floors.get(peer).copied())
+    }
+
+    /// Treats every id up to `high_water` from `peer` as seen, for a sender reconnecting as a new peer.
+    pub(crate) fn raise_floor(&mut self, peer: PeerId, high_water: MessageId) {
+        let floor = self.floors.entry(peer).or_insert(high_water);
+        *floor = (*floor).max(high_water);
+    }
+
+    pub(crate) fn record(&mut self, peer: PeerId, id: MessageId, response: Option<M>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let seen = self.seen.entry(peer).or_default();
+        if seen.len() == self.capacity {
+            seen.pop_front();
+        }
+        seen.push_back((id, response));
+    }
+
+    pub(crate) fn forget_peer(&mut self, peer: &PeerId) {
+        self.seen.remove(peer);
+        self.floors.remove(peer);
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use matchbox_socket::PeerId;
+
+/// State that can be sent as the difference to an earlier version of itself, see `DeltaEncoder`.
+pub trait TDelta: Clone + PartialEq + serde::Serialize + for<'de> serde::Deserialize<'de> {
+    type Delta: Clone + serde::Serialize + for<'de> serde::Deserialize<'de>;
+
+    /// What changed going from `baseline` to `self`.
+    fn diff(&self, baseline: &Self) -> Self::Delta;
+
+    /// Rebuilds the state by applying a delta made by `diff` on top of `self`, the baseline.
+    fn apply(&self, delta: &Self::Delta) -> Self;
+}
+
+/// What `DeltaEncoder::encode` produces, put it in one of your messages to send it.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(bound(serialize = "K: serde::Serialize", deserialize = "K: serde::Deserialize<'de>"))]
+pub enum DeltaUpdate<K, S: TDelta> {
+    /// The whole state, which becomes the new baseline for the deltas after it.
+    Keyframe { slot: K, keyframe: u32, state: S },
+    /// The difference to the keyframe numbered `keyframe`.
+    Delta { slot: K, keyframe: u32, delta: S::Delta },
+}
+
+struct EncoderSlot<S> {
+    keyframe: u32,
+    baseline: S,
+    last_sent: S,
+    since_keyframe: u32,
+}
+
+/// Turns continuous state, e.g. positions, into small updates for each peer, for sending on an unreliable
+/// channel.
+///
+/// Deltas are always made against the last keyframe rather than the previous update, so a lost update
+/// doesn't break the ones after it. A full keyframe goes out every `keyframe_interval` updates, which is
+/// also how long a peer that missed a keyframe goes without updates for that slot. Nothing is sent while
+/// the state doesn't change.
+///
+/// ```
+/// use trailrunner::prelude::*;
+///
+/// #[derive(serde::Serialize, serde::Deserialize, Clone, PartialEq, Debug)]
+/// struct Position { x: i32, y: i32 }
+///
+/// impl TDelta for Position {
+///     type Delta = (i32, i32);
+///
+///     fn diff(&self, baseline: &Self) -> Self::Delta {
+///         (self.x - baseline.x, self.y - baseline.y)
+///     }
+///
+///     fn apply(&self, delta: &Self::Delta) -> Self {
+///         Position { x: self.x + delta.0, y: self.y + delta.1 }
+///     }
+/// }
+///
+/// let peer = PeerId(Default::default());
+/// let mut encoder = DeltaEncoder::new(30);
+/// let mut decoder = DeltaDecoder::new();
+///
+/// for position in [Position { x: 0, y: 0 }, Position { x: 3, y: -1 }] {
+///     let update = encoder.encode(peer, 0u8, &position).unwrap();
+///     assert_eq!(decoder.decode(peer, update), Some(position.clone()));
+///     // Unchanged, nothing to send
+///     assert!(encoder.encode(peer, 0u8, &position).is_none());
+/// }
+/// ```
+pub struct DeltaEncoder<K, S> {
+    keyframe_interval: u32,
+    slots: HashMap<(PeerId, K), EncoderSlot<S>>,
+}
+
+impl<K: Eq + Hash + Clone, S: TDelta> DeltaEncoder<K, S> {
+    /// `keyframe_interval` is how many updates go out per slot before another full keyframe is sent.
+    pub fn new(keyframe_interval: u32) -> Self {
+        Self {
+            keyframe_interval: keyframe_interval.max(1),
+            slots: HashMap::new(),
+        }
+    }
+
+    /// The update to send `peer` for `slot` now that it's `state`, or `None` if it hasn't changed since
+    /// the last update.
+    pub fn encode(&mut self, peer: PeerId, slot: K, state: &S) -> Option<DeltaUpdate<K, S>> {
+        let key = (peer, slot.clone());
+        let Some(existing) = self.slots.get_mut(&key) else {
+            self.slots.insert(key, EncoderSlot {
+                keyframe: 0,
+                baseline: state.clone(),
+                last_sent: state.clone(),
+                since_keyframe: 0,
+            });
+            return Some(DeltaUpdate::Keyframe { slot, keyframe: 0, state: state.clone() });
+        };
+
+        if existing.last_sent == *state {
+            return None;
+        }
+        existing.last_sent = state.clone();
+        existing.since_keyframe += 1;
+
+        if existing.since_keyframe >= self.keyframe_interval {
+            existing.keyframe = existing.keyframe.wrapping_add(1);
+            existing.baseline = state.clone();
+            existing.since_keyframe = 0;
+            return Some(DeltaUpdate::Keyframe { slot, keyframe: existing.keyframe, state: state.clone() });
+        }
+
+        Some(DeltaUpdate::Delta {
+            slot,
+            keyframe: existing.keyframe,
+            delta: state.diff(&existing.baseline),
+        })
+    }
+
+    /// Makes the next update for `slot` to `peer` a keyframe, e.g. when they report being out of sync.
+    pub fn reset(&mut self, peer: PeerId, slot: &K) {
+        self.slots.retain(|(p, s), _| !(*p == peer && s == slot));
+    }
+
+    /// Forgets every baseline kept for `peer`, call this when they disconnect.
+    pub fn forget_peer(&mut self, peer: PeerId) {
+        self.slots.retain(|(p, _), _| *p != peer);
+    }
+}
+
+/// Rebuilds the state sent by a peer's `DeltaEncoder`.
+pub struct DeltaDecoder<K, S> {
+    baselines: HashMap<(PeerId, K), (u32, S)>,
+}
+
+impl<K: Eq + Hash + Clone, S: TDelta> Default for DeltaDecoder<K, S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Eq + Hash + Clone, S: TDelta> DeltaDecoder<K, S> {
+    pub fn new() -> Self {
+        Self {
+            baselines: HashMap::new(),
+        }
+    }
+
+    /// The state `update` from `peer` describes, or `None` if it's a delta against a keyframe we never got
+    /// (it was lost or arrived out of order) or an outdated keyframe. Updates resume with the next keyframe.
+    pub fn decode(&mut self, peer: PeerId, update: DeltaUpdate<K, S>) -> Option<S> {
+        match update {
+            DeltaUpdate::Keyframe { slot, keyframe, state } => {
+                let key = (peer, slot);
+                // A keyframe older than the one we have arrived late, the deltas after it are already gone
+                if let Some((current, _)) = self.baselines.get(&key) {
+                    if keyframe.wrapping_sub(*current) > u32::MAX / 2 {
+                        return None;
+                    }
+                }
+                self.baselines.insert(key, (keyframe, state.clone()));
+                Some(state)
+            }
+            DeltaUpdate::Delta { slot, keyframe, delta } => {
+                let (baseline_keyframe, baseline) = self.baselines.get(&(peer, slot))?;
+                match *baseline_keyframe == keyframe {
+                    true => Some(baseline.apply(&delta)),
+                    false => None,
+                }
+            }
+        }
+    }
+
+    /// Forgets every baseline received from `peer`, call this when they disconnect.
+    pub fn forget_peer(&mut self, peer: PeerId) {
+        self.baselines.retain(|(p, _), _| *p != peer);
+    }
+}
@@ -0,0 +1,107 @@
+use std::fmt;
+use matchbox_socket::PeerId;
+
+/// Why a `Message` was refused by the `MessageQueue`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MessageError {
+    /// More than one targeting option was set on the same message, so it's ambiguous who it should go to.
+    ConflictingTargets {
+        first: &'static str,
+        second: &'static str,
+    },
+    /// The queue already holds as many bytes as it was limited to with `MessageQueue::with_max_bytes`.
+    QueueFull {
+        pending_bytes: usize,
+        max_bytes: usize,
+    },
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageError::ConflictingTargets { first, second } => {
+                write!(f, "message targets {first} but was also told to target {second}")
+            }
+            MessageError::QueueFull { pending_bytes, max_bytes } => {
+                write!(f, "message queue is full, holding {pending_bytes} of at most {max_bytes} bytes")
+            }
+        }
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+/// Why a message didn't go out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendError {
+    /// The message couldn't be serialized.
+    Serialize(String),
+    /// The message was for a peer that isn't connected.
+    NoSuchPeer { peer: PeerId },
+    /// The channel had no room left for the message.
+    BufferFull { peer: PeerId },
+    /// The channel was closed before the message could be handed to it, usually because the socket closed.
+    ChannelClosed { peer: PeerId },
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Serialize(e) => write!(f, "failed to serialize message: {e}"),
+            SendError::NoSuchPeer { peer } => write!(f, "peer {peer} isn't connected"),
+            SendError::BufferFull { peer } => write!(f, "channel to {peer} is full"),
+            SendError::ChannelClosed { peer } => write!(f, "channel closed while sending to {peer}"),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+/// Something that went wrong while ticking the `NetworkManager`, see `NetworkManager::tick`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetworkError {
+    /// A packet from `peer` couldn't be deserialized, e.g. because they run a different version.
+    Deserialize { peer: PeerId, error: String },
+    /// A packet couldn't be serialized or handed to the socket.
+    Send(SendError),
+    /// The socket's message loop ended with an error.
+    SocketFailed,
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkError::Deserialize { peer, error } => write!(f, "failed to deserialize packet from {peer}: {error}"),
+            NetworkError::Send(e) => e.fmt(f),
+            NetworkError::SocketFailed => write!(f, "the socket failed"),
+        }
+    }
+}
+
+impl std::error::Error for NetworkError {}
+
+/// A string that isn't a valid `PeerId`, see `peer_id_from_str`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerIdParseError {
+    pub input: String,
+}
+
+impl fmt::Display for PeerIdParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} is not a valid peer id", self.input)
+    }
+}
+
+impl std::error::Error for PeerIdParseError {}
+
+/// A snapshot couldn't be saved or loaded, see `NetworkManager::snapshot`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotError(pub String);
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid snapshot: {}", self.0)
+    }
+}
+
+impl std::error::Error for SnapshotError {}
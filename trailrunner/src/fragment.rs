@@ -0,0 +1,224 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+use matchbox_socket::PeerId;
+
+/// The smallest threshold `NetworkManager::with_fragment_threshold` accepts, so every fragment but the last
+/// carries at least `MIN_FRAGMENT_DATA` bytes.
+pub const MIN_FRAGMENT_THRESHOLD: usize = 512;
+/// How many bytes of incomplete packets are held per peer unless set with
+/// `NetworkManager::with_max_reassembly_bytes`. Packets bigger than this can't be put back together.
+pub const DEFAULT_MAX_REASSEMBLY_BYTES: usize = 16 * 1024 * 1024;
+/// How long an incomplete packet waits for its missing fragments unless set with
+/// `NetworkManager::with_reassembly_timeout`.
+pub const DEFAULT_REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many incomplete packets are kept per peer. Fragments lost on an unreliable channel leave their packet
+/// incomplete forever, so the oldest is dropped to make room.
+const MAX_PARTIAL_PER_PEER: usize = 32;
+/// The least a fragment other than the last of its packet carries, whatever the sender's threshold was.
+const MIN_FRAGMENT_DATA: usize = 256;
+
+/// A packet whose fragments are still arriving.
+struct Partial {
+    count: usize,
+    fragments: BTreeMap<usize, Vec<u8>>,
+    started_at: Duration,
+}
+
+/// The incomplete packets from one peer.
+#[derive(Default)]
+struct PeerPartials {
+    partials: BTreeMap<u64, Partial>,
+    bytes: usize,
+}
+
+impl PeerPartials {
+    fn evict_oldest(&mut self) {
+        if let Some((_, partial)) = self.partials.pop_first() {
+            self.bytes -= partial.fragments.values().map(Vec::len).sum::<usize>();
+        }
+    }
+}
+
+/// Puts packets split up by `NetworkManager::with_fragment_threshold` back together, in whatever order their
+/// fragments arrive.
+///
+/// Fragments come straight off the wire before any signature is checked, so everything held is bounded: the
+/// fragment count by what could fit in `max_bytes`, the bytes held per peer by `max_bytes`, and how long an
+/// incomplete packet is kept by the timeout passed to `expire`.
+pub(crate) struct Reassembly {
+    max_bytes: usize,
+    peers: HashMap<PeerId, PeerPartials>,
+}
+
+impl Default for Reassembly {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_REASSEMBLY_BYTES)
+    }
+}
+
+impl Reassembly {
+    pub(crate) fn new(max_bytes: usize) -> Self {
+        Self { max_bytes, peers: HashMap::new() }
+    }
+
+    /// Adds fragment `index` of `count` of packet `group` from `peer`, returning the whole packet once this
+    /// was the last fragment missing. Fragments that couldn't belong to a packet of at most `max_bytes` are
+    /// dropped.
+    pub(crate) fn insert(
+        &mut self,
+        peer: PeerId,
+        group: u64,
+        index: u32,
+        count: u32,
+        data: Vec<u8>,
+        now: Duration,
+    ) -> Option<Vec<u8>> {
+        let (index, count) = (index as usize, count as usize);
+        let is_last = index + 1 == count;
+        if index >= count
+            || count > self.max_bytes.div_ceil(MIN_FRAGMENT_DATA)
+            || (!is_last && data.len() < MIN_FRAGMENT_DATA)
+            || data.len() > self.max_bytes
+        {
+            return None;
+        }
+
+        let peer_partials = self.peers.entry(peer).or_default();
+        if !peer_partials.partials.contains_key(&group) && peer_partials.partials.len() >= MAX_PARTIAL_PER_PEER {
+            peer_partials.evict_oldest();
+        }
+        let partial = peer_partials.partials.get(&group);
+        if partial.is_some_and(|partial| partial.count != count || partial.fragments.contains_key(&index)) {
+            return None;
+        }
+        // Make room by dropping the oldest incomplete packets, possibly this one
+        let started = partial.is_some();
+        while peer_partials.bytes + data.len() > self.max_bytes && !peer_partials.partials.is_empty() {
+            peer_partials.evict_oldest();
+        }
+        if started && !peer_partials.partials.contains_key(&group) {
+            return None;
+        }
+
+        peer_partials.bytes += data.len();
+        let partial = peer_partials.partials.entry(group).or_insert_with(|| Partial {
+            count,
+            fragments: BTreeMap::new(),
+            started_at: now,
+        });
+        partial.fragments.insert(index, data);
+        if partial.fragments.len() < count {
+            return None;
+        }
+
+        let partial = peer_partials.partials.remove(&group)?;
+        let whole: Vec<u8> = partial.fragments.into_values().flatten().collect();
+        peer_partials.bytes -= whole.len();
+        Some(whole)
+    }
+
+    /// Drops incomplete packets whose first fragment arrived longer than `timeout` before `now`.
+    pub(crate) fn expire(&mut self, now: Duration, timeout: Duration) {
+        for peer_partials in self.peers.values_mut() {
+            let PeerPartials { partials, bytes } = peer_partials;
+            partials.retain(|_, partial| {
+                let keep = now.saturating_sub(partial.started_at) <= timeout;
+                if !keep {
+                    *bytes -= partial.fragments.values().map(Vec::len).sum::<usize>();
+                }
+                keep
+            });
+        }
+        self.peers.retain(|_, peer_partials| !peer_partials.partials.is_empty());
+    }
+
+    /// Drops the incomplete packets from `peer`, e.g. because they disconnected mid-transfer.
+    pub(crate) fn forget_peer(&mut self, peer: &PeerId) {
+        self.peers.remove(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(n: u128) -> PeerId {
+        PeerId(uuid::Uuid::from_u128(n))
+    }
+
+    fn fragment(byte: u8) -> Vec<u8> {
+        vec![byte; MIN_FRAGMENT_DATA]
+    }
+
+    #[test]
+    fn reassembles_out_of_order() {
+        let mut reassembly = Reassembly::default();
+        let now = Duration::ZERO;
+        assert_eq!(reassembly.insert(peer(1), 0, 2, 3, vec![3], now), None);
+        assert_eq!(reassembly.insert(peer(1), 0, 0, 3, fragment(1), now), None);
+        let whole = reassembly.insert(peer(1), 0, 1, 3, fragment(2), now).unwrap();
+        assert_eq!(whole.len(), 2 * MIN_FRAGMENT_DATA + 1);
+        assert_eq!(whole[0], 1);
+        assert_eq!(whole[MIN_FRAGMENT_DATA], 2);
+        assert_eq!(whole[2 * MIN_FRAGMENT_DATA], 3);
+    }
+
+    #[test]
+    fn ignores_duplicates_and_mismatched_counts() {
+        let mut reassembly = Reassembly::default();
+        let now = Duration::ZERO;
+        assert_eq!(reassembly.insert(peer(1), 0, 0, 2, fragment(1), now), None);
+        assert_eq!(reassembly.insert(peer(1), 0, 0, 2, fragment(9), now), None);
+        assert_eq!(reassembly.insert(peer(1), 0, 1, 3, vec![2], now), None);
+        let whole = reassembly.insert(peer(1), 0, 1, 2, vec![2], now).unwrap();
+        assert_eq!(whole[0], 1);
+    }
+
+    #[test]
+    fn keeps_groups_of_different_peers_apart() {
+        let mut reassembly = Reassembly::default();
+        let now = Duration::ZERO;
+        assert_eq!(reassembly.insert(peer(1), 0, 0, 2, fragment(1), now), None);
+        assert_eq!(reassembly.insert(peer(2), 0, 1, 2, vec![2], now), None);
+        assert!(reassembly.insert(peer(1), 0, 1, 2, vec![2], now).is_some());
+    }
+
+    #[test]
+    fn rejects_impossible_fragments() {
+        let mut reassembly = Reassembly::new(1024);
+        let now = Duration::ZERO;
+        // Too many fragments for what fits in the budget
+        assert_eq!(reassembly.insert(peer(1), 0, 0, u32::MAX, fragment(1), now), None);
+        assert_eq!(reassembly.insert(peer(1), 1, 0, 5, fragment(1), now), None);
+        // Index out of range, and a short fragment that isn't the last
+        assert_eq!(reassembly.insert(peer(1), 2, 2, 2, vec![1], now), None);
+        assert_eq!(reassembly.insert(peer(1), 3, 0, 2, vec![1], now), None);
+        assert!(reassembly.peers.get(&peer(1)).is_none_or(|partials| partials.partials.is_empty()));
+    }
+
+    #[test]
+    fn evicts_oldest_when_over_budget() {
+        let mut reassembly = Reassembly::new(2 * MIN_FRAGMENT_DATA);
+        let now = Duration::ZERO;
+        assert_eq!(reassembly.insert(peer(1), 0, 0, 2, fragment(1), now), None);
+        assert_eq!(reassembly.insert(peer(1), 1, 0, 2, fragment(2), now), None);
+        assert_eq!(reassembly.insert(peer(1), 2, 0, 2, fragment(3), now), None);
+        let partials = &reassembly.peers[&peer(1)];
+        assert_eq!(partials.partials.keys().copied().collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(partials.bytes, 2 * MIN_FRAGMENT_DATA);
+        // Group 0 was dropped, its last fragment can't complete it
+        assert_eq!(reassembly.insert(peer(1), 0, 1, 2, vec![1], now), None);
+    }
+
+    #[test]
+    fn expires_stale_groups() {
+        let mut reassembly = Reassembly::default();
+        assert_eq!(reassembly.insert(peer(1), 0, 0, 2, fragment(1), Duration::ZERO), None);
+        assert_eq!(reassembly.insert(peer(1), 1, 0, 2, fragment(2), Duration::from_secs(5)), None);
+        reassembly.expire(Duration::from_secs(11), DEFAULT_REASSEMBLY_TIMEOUT);
+        assert_eq!(reassembly.peers[&peer(1)].partials.keys().copied().collect::<Vec<_>>(), vec![1]);
+        assert_eq!(reassembly.peers[&peer(1)].bytes, MIN_FRAGMENT_DATA);
+        reassembly.expire(Duration::from_secs(16), DEFAULT_REASSEMBLY_TIMEOUT);
+        assert!(reassembly.peers.is_empty());
+    }
+}
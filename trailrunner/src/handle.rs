@@ -0,0 +1,58 @@
+use std::sync::{Arc, Mutex};
+use crate::prelude::*;
+
+#[derive(Debug, Default)]
+struct HandleState {
+    id: Option<MessageId>,
+    cancelled: bool,
+}
+
+/// Refers to a message queued with `MessageQueue::enqueue`, to follow it after it's gone into the queue.
+///
+/// The message's id is only known once a tick sends it, look it up with `id` to e.g. check
+/// `NetworkManager::ack_status`. Clones refer to the same message.
+#[derive(Debug, Clone, Default)]
+pub struct MessageHandle {
+    state: Arc<Mutex<HandleState>>,
+}
+
+impl MessageHandle {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// The id the message was sent with, `None` while it's still queued, or if it never went out because it
+    /// was cancelled, coalesced away or dropped.
+    pub fn id(&self) -> Option<MessageId> {
+        self.lock().id
+    }
+
+    /// Whether the message has been sent.
+    pub fn is_sent(&self) -> bool {
+        self.id().is_some()
+    }
+
+    /// Keeps the message from being sent, if it hasn't been yet. Returns whether it was still waiting, a
+    /// message already sent can't be called back.
+    pub fn cancel(&self) -> bool {
+        let mut state = self.lock();
+        if state.id.is_some() {
+            return false;
+        }
+        state.cancelled = true;
+        true
+    }
+
+    /// Whether `cancel` stopped the message from being sent.
+    pub fn is_cancelled(&self) -> bool {
+        self.lock().cancelled
+    }
+
+    pub(crate) fn assign(&self, id: MessageId) {
+        self.lock().id = Some(id);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HandleState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
@@ -1,10 +1,55 @@
 mod app;
+mod builder;
+mod channel;
+mod compression;
+mod conditions;
+mod dedup;
+mod delta;
+mod error;
+mod fragment;
+mod handle;
+mod lifecycle;
+mod minimal;
+mod peer;
 mod user;
 mod network;
+mod raw;
+mod registry;
+mod sequence;
+mod signing;
+mod snapshot;
+mod stats;
+mod stream;
+mod transfer;
+#[cfg(feature = "test")]
+mod testing;
+#[cfg(feature = "metrics")]
+mod telemetry;
 
 pub mod prelude {
     pub use super::app::*;
+    pub use super::builder::*;
+    pub use super::channel::*;
+    pub use super::compression::Compression;
+    pub use super::conditions::NetworkConditions;
+    pub use super::dedup::DEFAULT_DEDUP_WINDOW;
+    pub use super::delta::*;
+    pub use super::error::*;
+    pub use super::fragment::{DEFAULT_MAX_REASSEMBLY_BYTES, DEFAULT_REASSEMBLY_TIMEOUT, MIN_FRAGMENT_THRESHOLD};
+    pub use super::handle::*;
+    pub use super::lifecycle::ManagedMessageLoop;
+    pub use super::minimal::*;
+    pub use super::peer::*;
     pub use super::user::*;
     pub use super::network::*;
+    pub use super::raw::*;
+    pub use super::registry::*;
+    pub use super::signing::*;
+    pub use super::snapshot::*;
+    pub use super::stats::*;
+    pub use super::stream::{IncomingMessage, MessageStream};
+    pub use super::transfer::{StreamId, DEFAULT_STREAM_CHUNK_SIZE};
+    #[cfg(feature = "test")]
+    pub use super::testing::*;
     pub use matchbox_socket::*;
 }
@@ -0,0 +1,100 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use futures::task::AtomicWaker;
+use matchbox_socket::{Error, MessageLoopFuture};
+
+const LOOP_RUNNING: u8 = 0;
+const LOOP_FINISHED: u8 = 1;
+const LOOP_FAILED: u8 = 2;
+
+/// How the message loop ended, as reported by `ShutdownSignal::loop_outcome`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LoopOutcome {
+    Running,
+    Finished,
+    Failed,
+}
+
+/// Shared between a `NetworkManager` and the `ManagedMessageLoop` it handed out, so the manager can tell
+/// the loop to stop, and learn when it ended on its own.
+#[derive(Default)]
+pub(crate) struct ShutdownSignal {
+    stopped: AtomicBool,
+    outcome: AtomicU8,
+    waker: AtomicWaker,
+}
+
+impl ShutdownSignal {
+    pub(crate) fn stop(&self) {
+        self.stopped.store(true, Ordering::Release);
+        self.waker.wake();
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Acquire)
+    }
+
+    fn finish(&self, failed: bool) {
+        let outcome = match failed {
+            true => LOOP_FAILED,
+            false => LOOP_FINISHED,
+        };
+        self.outcome.store(outcome, Ordering::Release);
+    }
+
+    pub(crate) fn loop_outcome(&self) -> LoopOutcome {
+        match self.outcome.load(Ordering::Acquire) {
+            LOOP_RUNNING => LoopOutcome::Running,
+            LOOP_FINISHED => LoopOutcome::Finished,
+            _ => LoopOutcome::Failed,
+        }
+    }
+}
+
+/// The socket's message loop future, tied to the `NetworkManager` it was attached to with
+/// `NetworkManager::attach_message_loop`.
+///
+/// Drive it wherever you drove the plain loop future. It resolves on its own once the manager is closed or
+/// dropped, dropping the matchbox loop with it, so no background task outlives the manager.
+pub struct ManagedMessageLoop {
+    inner: Option<MessageLoopFuture>,
+    signal: Arc<ShutdownSignal>,
+}
+
+impl ManagedMessageLoop {
+    pub(crate) fn new(inner: MessageLoopFuture, signal: Arc<ShutdownSignal>) -> Self {
+        Self { inner: Some(inner), signal }
+    }
+
+    /// Whether the manager has asked this loop to stop.
+    pub fn is_stopped(&self) -> bool {
+        self.signal.is_stopped()
+    }
+}
+
+impl Future for ManagedMessageLoop {
+    type Output = Result<(), Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.signal.waker.register(cx.waker());
+        if self.signal.is_stopped() {
+            self.inner = None;
+            return Poll::Ready(Ok(()));
+        }
+
+        match self.inner.as_mut() {
+            Some(inner) => {
+                let result = inner.as_mut().poll(cx);
+                if let Poll::Ready(outcome) = &result {
+                    self.signal.finish(outcome.is_err());
+                    self.inner = None;
+                }
+                result
+            }
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
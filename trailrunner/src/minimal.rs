@@ -0,0 +1,103 @@
+use std::time::Duration;
+use matchbox_socket::{PeerId, WebRtcSocket};
+use crate::prelude::*;
+
+/// The user kept for every peer when running without your own `TApp`.
+#[derive(Debug, Clone)]
+pub struct DefaultUser {
+    pub peer_id: PeerId,
+}
+
+impl TUser for DefaultUser {
+    fn new(peer_id: PeerId) -> Self {
+        Self { peer_id }
+    }
+}
+
+/// Something that happened during a tick, collected for `NetworkManager::drain_events`.
+#[derive(Debug, Clone)]
+pub enum NetworkEvent<M> {
+    PeerConnected(PeerId),
+    PeerDisconnected(PeerId, DisconnectReason),
+    /// A message arrived. If the sender asked for an ack, they got an empty one.
+    Message {
+        id: MessageId,
+        from_peer: PeerId,
+        data: M,
+    },
+}
+
+/// A stand-in `TApp` that turns everything that happens into `NetworkEvent`s, for using the
+/// `NetworkManager` directly without implementing the trait. See `NetworkManager::new_minimal`.
+pub struct MinimalApp<M: TSerializableMessage> {
+    users: UserList<DefaultUser>,
+    message_queue: MessageQueue<DefaultUser, MinimalApp<M>, M>,
+    events: Vec<NetworkEvent<M>>,
+}
+
+impl<M: TSerializableMessage> Default for MinimalApp<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: TSerializableMessage> MinimalApp<M> {
+    pub fn new() -> Self {
+        Self {
+            users: UserList::new(),
+            message_queue: MessageQueue::new(),
+            events: Vec::new(),
+        }
+    }
+}
+
+impl<M: TSerializableMessage> TApp<DefaultUser> for MinimalApp<M> {
+    type Application = MinimalApp<M>;
+    type Message = M;
+
+    fn users(&mut self) -> &mut UserList<DefaultUser> {
+        &mut self.users
+    }
+
+    fn message_queue(&mut self) -> &mut MessageQueue<DefaultUser, Self::Application, Self::Message> {
+        &mut self.message_queue
+    }
+
+    fn receive(&mut self, id: MessageId, from_peer: PeerId, message: &Self::Message) -> ReceiveOutcome {
+        self.events.push(NetworkEvent::Message { id, from_peer, data: message.clone() });
+        ReceiveOutcome::Handled
+    }
+
+    fn tick(&mut self, _delta: Duration) {}
+
+    fn post_user_connected(&mut self, peer_id: PeerId) {
+        self.events.push(NetworkEvent::PeerConnected(peer_id));
+    }
+
+    fn post_user_disconnected(&mut self, peer_id: PeerId, reason: DisconnectReason) {
+        self.events.push(NetworkEvent::PeerDisconnected(peer_id, reason));
+    }
+}
+
+impl<M: TSerializableMessage> NetworkManager<DefaultUser, MinimalApp<M>, M> {
+    /// Creates a manager that doesn't need a `TApp`. Call `tick` as usual, then collect what happened with
+    /// `drain_events` and send with `send` and `broadcast`.
+    pub fn new_minimal(socket: WebRtcSocket) -> Self {
+        Self::new(socket, MinimalApp::new())
+    }
+
+    /// Everything that happened since the last call.
+    pub fn drain_events(&mut self) -> Vec<NetworkEvent<M>> {
+        std::mem::take(&mut self.app_mut().events)
+    }
+
+    /// Queues `data` to be sent to `peer` on the next tick.
+    pub fn send(&mut self, peer: PeerId, data: M) -> Result<MessageHandle, MessageError> {
+        self.app_mut().message_queue.enqueue(Message::new(data).to_peer(peer))
+    }
+
+    /// Queues `data` to be sent to every connected peer on the next tick.
+    pub fn broadcast(&mut self, data: M) -> Result<MessageHandle, MessageError> {
+        self.app_mut().message_queue.enqueue(Message::new(data))
+    }
+}
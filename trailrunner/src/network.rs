@@ -1,33 +1,177 @@
-use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
 use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::time::Duration;
-use log::{info, warn};
-use matchbox_socket::{PeerState, WebRtcSocket};
+use futures::task::noop_waker_ref;
+use futures_timer::Delay;
+use log::{warn, Level};
+use matchbox_socket::{Packet, PeerState, WebRtcSocket};
+use crate::conditions::{ConditionedLink, SimRng};
+use crate::dedup::DedupWindow;
+use crate::fragment::{Reassembly, DEFAULT_REASSEMBLY_TIMEOUT, MIN_FRAGMENT_THRESHOLD};
+use crate::sequence::Sequencer;
+use crate::lifecycle::{LoopOutcome, ShutdownSignal};
+use crate::stream::StreamFeed;
+use crate::transfer::OutgoingStream;
 use crate::prelude::*;
 
+/// Logs through the manager, so the sink set with `NetworkManager::with_log_sink` sees it.
+macro_rules! net_log {
+    ($manager:expr, $level:expr, $($arg:tt)+) => {
+        $manager.log($level, format_args!($($arg)+))
+    };
+}
+
 pub const CHANNEL_ID: usize = 0;
+/// How often `NetworkManager::disconnect_graceful` ticks while it waits for acks.
+pub const GRACEFUL_TICK_INTERVAL: Duration = Duration::from_millis(16);
+/// How much each new round trip sample moves the smoothed round trip time of a peer.
+const RTT_SMOOTHING: f64 = 0.125;
+/// How many received packets are held while paused unless set with `NetworkManager::with_pause_capacity`.
+pub const DEFAULT_PAUSE_CAPACITY: usize = 1024;
+/// How long a peer has to finish connecting once we have messages for them, unless set with
+/// `NetworkManager::with_connect_timeout`.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long messages on a reliable `ChannelOrdering::Sequenced` channel are held back waiting on one sent before
+/// them, unless set with `NetworkManager::with_sequence_timeout`.
+pub const DEFAULT_SEQUENCE_TIMEOUT: Duration = Duration::from_secs(1);
+/// How many times a message with `Message::with_ack_timeout` is sent again before giving up, unless set with
+/// `Message::with_ack_retries` or `NetworkManager::with_ack_retries`.
+pub const DEFAULT_ACK_RETRIES: u32 = 3;
+/// How long a peer that left is remembered, to report them coming back with `TApp::on_peer_rejoined`, unless
+/// set with `NetworkManager::with_rejoin_window`.
+pub const DEFAULT_REJOIN_WINDOW: Duration = Duration::from_secs(60);
+/// How much each drained message moves `NetworkStats::avg_queue_latency`.
+const QUEUE_LATENCY_SMOOTHING: f64 = 0.125;
+/// How many completed message ids `NetworkManager::ack_status` remembers.
+const RECENTLY_ACKED_CAPACITY: usize = 256;
 pub type MessageId = usize;
 pub type FromPeerId = PeerId;
 
 /// The message queue are messages that will be sent to other peers. The messages are sent in the order they are added to the queue.
 pub struct MessageQueue<U: TUser, A: TApp<U>, M: TSerializableMessage> {
     messages: Vec<Message<U, A, M>>,
+    /// The manager's clock as of its last tick, to stamp messages with when they're queued.
+    now: Duration,
+    /// How many bytes the queued messages take up on the wire.
+    bytes: usize,
+    max_bytes: Option<usize>,
     _phantom_data: PhantomData<(U, M)>,
 }
 
+impl<U: TUser, A: TApp<U>, M: TSerializableMessage> Default for MessageQueue<U, A, M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<U: TUser, A: TApp<U>, M: TSerializableMessage> MessageQueue<U, A, M> {
     pub fn new() -> Self {
         Self {
             messages: Vec::new(),
+            now: Duration::ZERO,
+            bytes: 0,
+            max_bytes: None,
             _phantom_data: PhantomData
         }
     }
 
-    pub fn enqueue(&mut self, message: Message<U, A, M>) {
+    /// Makes `enqueue` refuse messages once the queued ones take up `max_bytes` on the wire, a backpressure
+    /// signal for payloads whose size varies too much for counting messages to mean anything.
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    pub(crate) fn set_max_bytes(&mut self, max_bytes: Option<usize>) {
+        self.max_bytes = max_bytes;
+    }
+
+    /// Queues a message to be sent on the next tick.
+    ///
+    /// Fails if the message was built with conflicting options, or wouldn't fit under the limit set with
+    /// `with_max_bytes`, in which case it is not queued. The returned handle follows the message from here on,
+    /// e.g. to learn its id or cancel it.
+    pub fn enqueue(&mut self, mut message: Message<U, A, M>) -> Result<MessageHandle, MessageError> {
+        message.validate()?;
+        message.encoded_len = message.encoded_len();
+        if let Some(max_bytes) = self.max_bytes {
+            if self.bytes + message.encoded_len > max_bytes {
+                return Err(MessageError::QueueFull { pending_bytes: self.bytes, max_bytes });
+            }
+        }
+        self.bytes += message.encoded_len;
+
+        let handle = MessageHandle::new();
+        message.queued_at = self.now;
+        message.handle = Some(handle.clone());
         self.messages.push(message);
+        Ok(handle)
+    }
+
+    /// How many messages are waiting for the next tick.
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// How many bytes the queued messages take up on the wire, counting a message once however many peers
+    /// it goes to.
+    pub fn pending_bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// The messages waiting for the next tick, in the order they'll be sent.
+    pub fn peek(&self) -> &[Message<U, A, M>] {
+        &self.messages
+    }
+
+    /// Takes every queued message matching `predicate` out of the queue before it's sent, e.g. to collapse
+    /// several position updates into the latest one. Returns how many were removed.
+    pub fn remove_if(&mut self, mut predicate: impl FnMut(&Message<U, A, M>) -> bool) -> usize {
+        let before = self.messages.len();
+        let mut removed_bytes = 0;
+        self.messages.retain(|message| {
+            let remove = predicate(message);
+            if remove {
+                removed_bytes += message.encoded_len;
+            }
+            !remove
+        });
+        self.bytes -= removed_bytes;
+        before - self.messages.len()
+    }
+
+    /// Reorders the queued messages, they're sent in the resulting order. The sort is stable.
+    pub fn sort_by(&mut self, compare: impl FnMut(&Message<U, A, M>, &Message<U, A, M>) -> std::cmp::Ordering) {
+        self.messages.sort_by(compare);
+    }
+
+    /// Points messages queued for `old` at `new` instead.
+    pub(crate) fn retarget(&mut self, old: PeerId, new: PeerId) {
+        for message in self.messages.iter_mut() {
+            if matches!(message.target, Target::Peer(peer) if peer == old) {
+                message.target = Target::Peer(new);
+            }
+        }
+    }
+
+    pub(crate) fn set_clock(&mut self, now: Duration) {
+        self.now = now;
     }
 
     pub(crate) fn drain(&mut self, range: std::ops::RangeFull) -> Vec<Message<U, A, M>> {
+        self.bytes = 0;
         self.messages.drain(range).collect()
     }
 }
@@ -40,106 +184,797 @@ where
     M: serde::Serialize + for<'de> serde::Deserialize<'de> + Clone + Send + 'static
 {}
 
+/// Everything that goes over the wire is one of these.
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(bound = "M: TSerializableMessage")]
+enum WirePacket<M: TSerializableMessage> {
+    Message(PackedMessage<M>),
+    /// The responses to every must-ack message received from a peer within a single tick.
+    AckBatch(Vec<PackedAck<M>>),
+    /// Another packet, serialized, along with its signature. Only used when a signer is set.
+    Signed { payload: Vec<u8>, signature: Vec<u8> },
+    /// The next piece of a stream started with `NetworkManager::send_stream`.
+    StreamChunk { stream: StreamId, data: Vec<u8> },
+    /// The last packet of a stream, `aborted` if the sender couldn't read all of it.
+    StreamEnd { stream: StreamId, aborted: bool },
+    /// The sender is closing their connection to us, sent by `NetworkManager::close` and `kick`.
+    Goodbye,
+    /// Sent every interval set with `NetworkManager::with_heartbeat`, so quiet peers still hear from us.
+    Heartbeat,
+    /// Piece `index` of `count` of a packet too big to send whole, see `NetworkManager::with_fragment_threshold`.
+    /// Fragments of the same packet share a `group`, unique per sender.
+    Fragment { group: u64, index: u32, count: u32, data: Vec<u8> },
+    /// The metadata the sender introduces themselves with once connected, see `NetworkManager::with_hello`.
+    Hello(Vec<u8>),
+    /// Another packet, serialized, numbered in the order it was sent to us on a `ChannelOrdering::Sequenced`
+    /// channel.
+    Sequenced { seq: u64, packet: Vec<u8> },
+}
+
 #[derive(serde::Serialize, serde::Deserialize)]
 #[serde(bound = "M: TSerializableMessage")]
 struct PackedMessage<M: TSerializableMessage> {
     id: MessageId,
-    is_ack: bool,
     must_ack: bool,
+    trace_id: Option<u128>,
+    origin: Option<Origin>,
+    exactly_once: bool,
     data: M,
 }
 
-impl<T: TSerializableMessage> PackedMessage<T> {
+/// Serializes exactly like `WirePacket::Message` without owning the message, for measuring its size.
+#[derive(serde::Serialize)]
+enum WirePacketRef<'a, M: serde::Serialize> {
+    Message {
+        id: MessageId,
+        must_ack: bool,
+        trace_id: Option<u128>,
+        origin: Option<Origin>,
+        exactly_once: bool,
+        data: &'a M,
+    },
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(bound = "M: TSerializableMessage")]
+struct PackedAck<M: TSerializableMessage> {
+    id: MessageId,
+    data: AckResult<M>,
+}
+
+/// Decides whether a peer, given their user, should receive a message.
+pub type PeerFilter<U> = Box<dyn Fn(&PeerId, &U) -> bool>;
+
+/// How likely a peer, given their user, is to be picked for a message sent to random peers.
+pub type PeerWeight<U> = Box<dyn Fn(&PeerId, &U) -> f64>;
+
+/// How many times a message may be handed to the receiving app.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Delivery {
+    /// The default. A message that is delivered again, e.g. because it was retransmitted, is processed again.
+    #[default]
+    AtLeastOnce,
+    /// The receiver remembers the ids of recent messages from each peer and drops repeats, answering them
+    /// with the same ack as the first time. Costs a little memory on the receiving side.
+    ExactlyOnce,
+}
+
+/// Where the manager is in connecting to the signaling server, see `NetworkManager::connection_status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    /// Waiting for the signaling server to assign us a peer id.
+    Connecting,
+    /// Registered with the signaling server, peers can find us.
+    Connected,
+    /// Closed with `NetworkManager::close`, or the message loop ended.
+    Disconnected,
+    /// The message loop ended with an error, e.g. the signaling server couldn't be reached.
+    Failed,
+}
+
+/// Who a `Message` will be sent to.
+pub enum Target<U> {
+    /// Every connected peer.
+    Broadcast,
+    /// A single peer.
+    Peer(PeerId),
+    /// The given peers, those not connected when the message is sent are skipped.
+    Peers(Vec<PeerId>),
+    /// Every connected peer whose user passes the filter, checked when the message is sent.
+    Where(PeerFilter<U>),
+    /// The given number of connected peers with the lowest round trip time, checked when the message is sent.
+    Best(usize),
+    /// Every connected peer but one.
+    AllExcept(PeerId),
+    /// The given number of connected peers picked at random, checked when the message is sent. The same seed
+    /// picks the same peers out of the same set. With a weight, peers are picked in proportion to it.
+    Random { count: usize, seed: u64, weight: Option<PeerWeight<U>> },
+}
+
+impl<U> Target<U> {
+    fn describe(&self) -> &'static str {
+        match self {
+            Target::Broadcast => "all peers",
+            Target::Peer(_) => "a single peer",
+            Target::Peers(_) => "a list of peers",
+            Target::Where(_) => "peers matching a filter",
+            Target::Best(_) => "the lowest latency peers",
+            Target::AllExcept(_) => "all peers but one",
+            Target::Random { .. } => "random peers",
+        }
+    }
+
+    fn is_broadcast(&self) -> bool {
+        matches!(self, Target::Broadcast)
+    }
+}
+
+impl<U> std::fmt::Debug for Target<U> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Broadcast => write!(f, "Broadcast"),
+            Target::Peer(peer) => f.debug_tuple("Peer").field(peer).finish(),
+            Target::Peers(peers) => f.debug_tuple("Peers").field(peers).finish(),
+            Target::Where(_) => write!(f, "Where(..)"),
+            Target::Best(count) => f.debug_tuple("Best").field(count).finish(),
+            Target::AllExcept(peer) => f.debug_tuple("AllExcept").field(peer).finish(),
+            Target::Random { count, seed, .. } => f.debug_struct("Random")
+                .field("count", count)
+                .field("seed", seed)
+                .finish_non_exhaustive(),
+        }
+    }
+}
+
+/// Called with the application, the id of the message, the peer that answered it and whether they acked or
+/// declined it.
+pub type AckHandler<A, M> = Box<dyn FnMut(&mut A, MessageId, FromPeerId, &AckResult<M>)>;
+
+/// Called once with the application, the id of the message, every peer that answered it and their answers.
+pub type BroadcastAckHandler<A, M> = Box<dyn FnMut(&mut A, MessageId, &[PeerId], &[(PeerId, AckResult<M>)])>;
+
+/// Called with the application and the reason a message couldn't be sent.
+pub type SendErrorHandler<A> = Box<dyn FnMut(&mut A, &SendError)>;
+
+/// Called with the application, the id of a message whose ack deadline passed and the peers that hadn't
+/// acked it.
+pub type TimeoutHandler<A> = Box<dyn FnMut(&mut A, MessageId, &[PeerId])>;
+
+/// Picks the channel a message goes out on from what it carries, `None` leaves it on the default channel.
+pub type ChannelRouter<M> = Box<dyn Fn(&M) -> Option<usize>>;
+
+/// Builds the message for each peer of a tailored broadcast, `None` skips them.
+pub type TailoredPayload<U, M> = Box<dyn FnMut(&PeerId, &U) -> Option<M>>;
+
+/// Names the logical user behind a peer for `NetworkManager::with_reconnect_dedup`, hashed.
+pub type ReconnectKey<U> = Box<dyn Fn(&PeerId, &U) -> Option<u64>>;
+
+/// Receives the crate's diagnostics instead of the `log` macros, see `NetworkManager::with_log_sink`.
+pub type LogSink = Box<dyn Fn(Level, &str)>;
+
+/// Called with the application and a peer that just connected, returning what to send them.
+pub type JoinSender<U, T, M> = Box<dyn FnMut(&mut T, PeerId) -> Option<Message<U, T, M>>>;
+
+/// Called with the application, the user of the peer that answered, the id of the message, the peer and their
+/// answer.
+pub type UserAckHandler<A, U, M> = Box<dyn FnMut(&mut A, &mut U, MessageId, FromPeerId, &AckResult<M>)>;
+
+enum AckCallback<A, U, M> {
+    PerPeer(AckHandler<A, M>),
+    PerUser(UserAckHandler<A, U, M>),
+    Broadcast(BroadcastAckHandler<A, M>),
 }
 
 pub struct Message<U: TUser, T: TApp<U>, M: TSerializableMessage> {
     id: MessageId,
-    to_peer: Option<PeerId>,
+    target: Target<U>,
+    target_error: Option<MessageError>,
     data: M,
-    ack_handler: Option<Box<dyn FnMut(&mut T::Application, MessageId, FromPeerId, &M)>>,
+    ack_handler: Option<AckCallback<T::Application, U, M>>,
+    send_error_handler: Option<SendErrorHandler<T::Application>>,
+    ack_deadline: Option<Duration>,
+    ack_timeout: Option<Duration>,
+    ack_retries: Option<u32>,
+    timeout_handler: Option<TimeoutHandler<T::Application>>,
+    ordered: Option<bool>,
+    reliability: Option<Reliability>,
+    channel: Option<usize>,
+    trace_id: Option<u128>,
+    origin: Option<Origin>,
+    coalesce_key: Option<u64>,
+    delivery: Delivery,
+    quorum: Option<usize>,
+    loopback: bool,
+    fanout_rate: Option<usize>,
+    /// What `encoded_len` measured when the message was queued.
+    encoded_len: usize,
+    /// When the message was put in the `MessageQueue`, by the manager's clock.
+    queued_at: Duration,
+    handle: Option<MessageHandle>,
     _phantom_data: PhantomData<U>,
 }
 
 impl<U: TUser, T: TApp<U>, M: TSerializableMessage> Message<U, T, M> {
 
     /// Create a new message. Creating this doesn't send by itself, you must have access to a `MessageQueue` to send it.
-    /// If you leave `to_peer` as `None`, the message will be broad-casted to all peers.
+    /// If you don't pick a target, the message will be broad-casted to all peers. Only one target may be picked,
+    /// picking a second one makes `MessageQueue::enqueue` refuse the message.
     ///
     /// Example usage:
     /// ```rust
     /// use trailrunner::prelude::*;
-    /// use matchbox_socket::PeerId;
     ///
-    /// let message = Message::new("hello world".as_bytes().to_vec())
+    /// # fn send(queue: &mut MessageQueue<DefaultUser, MinimalApp<String>, String>, peer_id: PeerId) -> Result<(), MessageError> {
+    /// let message = Message::new("Hello".to_string())
     ///     // Optional: specify a peer to send the message to, otherwise it broadcasts to all peers.
-    ///     .to_peer(PeerId::from("some-peer-id"))
+    ///     .to_peer(peer_id)
     ///     // Optional: subscribe to a callback that peers must respond to.
-    ///     .with_ack_handler(|app, from_peer, data| {
-    ///         // Handle incoming messages here
+    ///     .with_ack_handler(|app, id, from_peer, ack| {
+    ///         // Handle their response here
     ///     });
-    /// // move `message` into an `enqueue` call on a `MessageQueue` to send the message
+    /// // Sends it on the next tick, or says why it can't be sent
+    /// let handle = queue.enqueue(message)?;
+    /// # Ok(())
+    /// # }
     /// ```
     ///
     pub fn new(data: M) -> Self {
         Self {
             id: 0, // gets set by the `NetworkManager` before sending
-            to_peer: None,
+            target: Target::Broadcast,
+            target_error: None,
             data,
             ack_handler: None,
+            send_error_handler: None,
+            ack_deadline: None,
+            ack_timeout: None,
+            ack_retries: None,
+            timeout_handler: None,
+            ordered: None,
+            reliability: None,
+            channel: None,
+            trace_id: None,
+            origin: None,
+            coalesce_key: None,
+            delivery: Delivery::AtLeastOnce,
+            quorum: None,
+            loopback: false,
+            fanout_rate: None,
+            encoded_len: 0,
+            queued_at: Duration::ZERO,
+            handle: None,
             _phantom_data: PhantomData
         }
     }
 
-    pub fn to_peer(mut self, to_peer: PeerId) -> Self {
-        self.to_peer = Some(to_peer);
+    pub fn to_peer(self, to_peer: PeerId) -> Self {
+        self.with_target(Target::Peer(to_peer))
+    }
+
+    /// Sends this message to each of `peers`, e.g. the players on one team, waiting on acks from each of them.
+    /// Peers that aren't connected when the message is sent are skipped, unlike with `to_peer` it isn't held
+    /// for them.
+    pub fn to_peers(self, peers: impl IntoIterator<Item = PeerId>) -> Self {
+        self.with_target(Target::Peers(peers.into_iter().collect()))
+    }
+
+    /// Sends this message to every connected peer whose user passes `filter`, e.g. to only reach players
+    /// within range of an event. The filter runs when the message is sent, peers without a user are skipped.
+    pub fn to_peers_where(self, filter: impl Fn(&PeerId, &U) -> bool + 'static) -> Self {
+        self.with_target(Target::Where(Box::new(filter)))
+    }
+
+    /// Sends this message to every connected peer whose user has `tag` among `TUser::tags`, e.g. all
+    /// spectators.
+    pub fn to_tag(self, tag: impl Into<String>) -> Self {
+        let tag = tag.into();
+        self.to_peers_where(move |_, user| user.tags().contains(&tag))
+    }
+
+    /// Passes a received message on to every other connected peer, e.g. from the host of a star topology.
+    /// The recipients see who sent it first and the id they gave it in `ReceiveContext::origin`, and the trace
+    /// id is kept. Messages that were already relayed keep their original origin.
+    pub fn relay(ctx: &ReceiveContext, data: M) -> Self {
+        let mut message = Self::new(data).to_all_except(ctx.from_peer);
+        message.origin = Some(ctx.origin.unwrap_or(Origin { peer: ctx.from_peer, id: ctx.id }));
+        message.trace_id = ctx.trace_id;
+        message
+    }
+
+    /// Sends this message to every connected peer except `peer`.
+    pub fn to_all_except(self, peer: PeerId) -> Self {
+        self.with_target(Target::AllExcept(peer))
+    }
+
+    /// Sends this message to the `count` connected peers with the lowest round trip time, e.g. to spread
+    /// gossip without reaching everyone at once. Round trip times are measured from acks, peers we haven't
+    /// measured yet are picked last.
+    pub fn to_best_peers(self, count: usize) -> Self {
+        self.with_target(Target::Best(count))
+    }
+
+    /// Sends this message to `count` connected peers picked at random when it's sent, the fan-out of gossip
+    /// protocols. The pick is reproducible: the same `seed` picks the same peers out of the same set, so vary
+    /// it between rounds.
+    pub fn to_random_peers(self, count: usize, seed: u64) -> Self {
+        self.with_target(Target::Random { count, seed, weight: None })
+    }
+
+    /// Like `to_random_peers`, but peers are picked in proportion to `weight`, e.g. favouring peers in the same
+    /// region. Peers with a weight of zero or less, or without a user, are never picked.
+    pub fn to_weighted_random_peers(
+        self,
+        count: usize,
+        seed: u64,
+        weight: impl Fn(&PeerId, &U) -> f64 + 'static
+    ) -> Self {
+        self.with_target(Target::Random { count, seed, weight: Some(Box::new(weight)) })
+    }
+
+    /// Who this message will be sent to.
+    pub fn target(&self) -> &Target<U> {
+        &self.target
+    }
+
+    /// What this message carries.
+    pub fn data(&self) -> &M {
+        &self.data
+    }
+
+    /// Checks that the options this message was built with don't contradict each other.
+    pub fn validate(&self) -> Result<(), MessageError> {
+        match &self.target_error {
+            Some(error) => Err(error.clone()),
+            None => Ok(()),
+        }
+    }
+
+    fn with_target(mut self, target: Target<U>) -> Self {
+        let same_peer = matches!((&self.target, &target), (Target::Peer(a), Target::Peer(b)) if a == b);
+
+        // Only the first conflict is remembered, it's the one that explains the mistake
+        if !self.target.is_broadcast() && !same_peer && self.target_error.is_none() {
+            self.target_error = Some(MessageError::ConflictingTargets {
+                first: self.target.describe(),
+                second: target.describe(),
+            });
+        }
+        self.target = target;
         self
     }
 
     /// Subscribes to a callback that peers must respond to.
     ///
-    /// Peers will be expected to respond back with a message unless they disconnect between the time your
-    /// client sent the message and the time the other peer(s) would have received it.
+    /// Peers will be expected to respond back, with what `TApp::ack_response` returned on their side, unless
+    /// they disconnect between the time your client sent the message and the time the other peer(s) would have
+    /// received it. A peer declining the message with `AckResult::Nack` still counts as having responded.
     ///
     /// If it was a broadcast, we wait until all have responded before calling the handlers on each.
     /// That way, if this is called on a broadcast, you can be sure that all peers have received the message.
     pub fn with_ack_handler(
         mut self,
-        handler: impl FnMut(&mut T::Application, MessageId, FromPeerId, &M) + 'static
+        handler: impl FnMut(&mut T::Application, MessageId, FromPeerId, &AckResult<M>) + 'static
+    ) -> Self {
+        self.ack_handler = Some(AckCallback::PerPeer(Box::new(handler)));
+        self
+    }
+
+    /// Like `with_ack_handler`, but the handler is also handed the user of the peer that acked, saving you
+    /// from looking them up while the application is borrowed. Peers that left before the message completed
+    /// have no user anymore and are skipped.
+    ///
+    /// While the handler runs the user is taken out of the `UserList`, and put back afterwards.
+    pub fn with_user_ack_handler(
+        mut self,
+        handler: impl FnMut(&mut T::Application, &mut U, MessageId, FromPeerId, &AckResult<M>) + 'static
+    ) -> Self {
+        self.ack_handler = Some(AckCallback::PerUser(Box::new(handler)));
+        self
+    }
+
+    /// Like `with_ack_handler`, but the handler fires a single time once everyone has acked, with the list
+    /// of peers that acked and what each of them responded with.
+    ///
+    /// This suits "everyone is ready, now proceed" logic better than a call per peer. Replaces any handler
+    /// set with `with_ack_handler`.
+    pub fn with_broadcast_ack_handler(
+        mut self,
+        handler: impl FnMut(&mut T::Application, MessageId, &[PeerId], &[(PeerId, AckResult<M>)]) + 'static
     ) -> Self {
-        self.ack_handler = Some(Box::new(handler));
+        self.ack_handler = Some(AckCallback::Broadcast(Box::new(handler)));
+        self
+    }
+
+    /// Like `with_broadcast_ack_handler`, but the handler fires as soon as `quorum` distinct peers have acked,
+    /// e.g. 3 out of 5 confirming. Acks arriving after that are ignored, and declines don't count towards it.
+    ///
+    /// If peers leave until fewer than `quorum` are left to ack, the handler fires once all remaining peers
+    /// have acked, so check how many peers it was given.
+    pub fn with_quorum_ack(
+        mut self,
+        quorum: usize,
+        handler: impl FnMut(&mut T::Application, MessageId, &[PeerId], &[(PeerId, AckResult<M>)]) + 'static
+    ) -> Self {
+        self.quorum = Some(quorum);
+        self.ack_handler = Some(AckCallback::Broadcast(Box::new(handler)));
+        self
+    }
+
+    /// Creates a message for a request whose response type is known through `TRequest`, so the handler
+    /// receives the response already unpacked. Acks that come back empty, declined or as some other kind of
+    /// message are logged and skipped.
+    pub fn request<R: TRequest<M>>(
+        request: R,
+        mut handler: impl FnMut(&mut T::Application, MessageId, FromPeerId, R::Response) + 'static
+    ) -> Self {
+        Self::new(request.into()).with_ack_handler(move |app, id, from_peer, response| {
+            match response {
+                AckResult::Ack(Some(response)) => match R::Response::try_from(response.clone()) {
+                    Ok(response) => handler(app, id, from_peer, response),
+                    Err(_) => warn!("Peer {from_peer} answered request {id} with the wrong kind of response"),
+                },
+                AckResult::Ack(None) => warn!("Peer {from_peer} answered request {id} without a response"),
+                AckResult::Nack(reason) => warn!("Peer {from_peer} declined request {id}: {reason}"),
+            }
+        })
+    }
+
+    /// Sends this message on a reliable channel that is ordered or unordered, so independent streams of
+    /// messages don't hold each other up waiting on ordering.
+    ///
+    /// If no reliable channel with that ordering was configured on the socket, any reliable channel is used,
+    /// and if there are none it goes out on the default channel.
+    pub fn ordered(mut self, ordered: bool) -> Self {
+        self.ordered = Some(ordered);
+        self
+    }
+
+    /// Sends this message on a reliable or unreliable channel of the socket, e.g. positions unreliably and
+    /// inventory changes reliably. Build the socket with both kinds of channel, see
+    /// `NetworkManagerBuilder::add_channel`. If it has no channel of that kind the message goes out on the
+    /// default channel. Combine `Reliability::Reliable` with `ordered` to also pick the ordering.
+    pub fn reliability(mut self, reliability: Reliability) -> Self {
+        self.reliability = Some(reliability);
+        self
+    }
+
+    /// Sends this message on the socket's channel with index `channel`, e.g. an unreliable channel for
+    /// position updates and a reliable one for chat. Takes precedence over `reliability`, `ordered` and the
+    /// channel router.
+    /// If the socket has no such channel the message goes out on the default channel, `CHANNEL_ID`.
+    pub fn on_channel(mut self, channel: usize) -> Self {
+        self.channel = Some(channel);
+        self
+    }
+
+    /// Attaches a correlation id that travels with the message and is handed to the receiver in
+    /// `ReceiveContext::trace_id`, so a message can be followed across peers in logs. Relays should pass it on.
+    pub fn with_trace_id(mut self, trace_id: u128) -> Self {
+        self.trace_id = Some(trace_id);
+        self
+    }
+
+    /// Picks whether the receiver may process this message more than once, see `Delivery`.
+    pub fn delivery(mut self, delivery: Delivery) -> Self {
+        self.delivery = delivery;
+        self
+    }
+
+    /// Marks this message as superseded by any later message with the same key queued before the next tick,
+    /// so only the most recent one of each key is sent, e.g. the latest state of some object. Superseded
+    /// messages are dropped silently, without calling their handlers.
+    ///
+    /// Keys are shared by the whole queue regardless of who messages go to, so include the recipient in the
+    /// key when that matters.
+    pub fn coalesce_key(mut self, key: impl Hash) -> Self {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        self.coalesce_key = Some(hasher.finish());
+        self
+    }
+
+    /// Called if this message fails to go out, either because it couldn't be serialized or because the
+    /// channel to a recipient was closed. For broadcasts this is called once per recipient that failed.
+    pub fn on_send_error(mut self, handler: impl FnMut(&mut T::Application, &SendError) + 'static) -> Self {
+        self.send_error_handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Gives up on the message if it isn't acked within `deadline` of being sent: `on_timeout` is called with
+    /// the peers that didn't ack, and it's dropped without calling the ack handler or being sent again. Acks
+    /// arriving after that go to `TApp::on_orphan_ack`.
+    ///
+    /// Peers are asked to ack the message even without an ack handler, so this works on its own.
+    pub fn ack_deadline(mut self, deadline: Duration) -> Self {
+        self.ack_deadline = Some(deadline);
+        self
+    }
+
+    /// Sends the message again to the peers that haven't acked it each time `timeout` passes without an ack,
+    /// up to the count set with `with_ack_retries`, for messages sent on unreliable channels. Once out of
+    /// retries it's given up on, `TApp::on_ack_timeout` is called for each peer that never acked, and
+    /// `on_timeout` with all of them.
+    ///
+    /// A peer whose ack got lost receives the message again, send it with `Delivery::ExactlyOnce` so they drop
+    /// the repeat and ack it again instead. Like `ack_deadline`, this asks for acks without an ack handler.
+    pub fn with_ack_timeout(mut self, timeout: Duration) -> Self {
+        self.ack_timeout = Some(timeout);
+        self
+    }
+
+    /// How many times `with_ack_timeout` sends the message again before giving up, the manager's
+    /// `NetworkManager::with_ack_retries` unless set.
+    pub fn with_ack_retries(mut self, retries: u32) -> Self {
+        self.ack_retries = Some(retries);
+        self
+    }
+
+    /// Called when the deadline set with `ack_deadline` passes, or the retries of `with_ack_timeout` run
+    /// out, before everyone acked, with the peers that didn't.
+    pub fn on_timeout(mut self, handler: impl FnMut(&mut T::Application, MessageId, &[PeerId]) + 'static) -> Self {
+        self.timeout_handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Also dispatch this message to our own `TApp::receive` when it is sent, with `from_peer` set to our
+    /// own peer id. This lets the same code path apply local and remote state changes.
+    ///
+    /// The local copy never expects an ack from ourselves. If the signaling server hasn't assigned us an id
+    /// yet, the local copy is skipped.
+    pub fn with_loopback(mut self) -> Self {
+        self.loopback = true;
+        self
+    }
+
+    /// Spreads sending this message over several ticks, going out to at most `peers_per_tick` recipients per
+    /// tick, to smooth out the bandwidth of a broadcast in a big room. Recipients are picked when the message
+    /// is first sent, those that leave before their turn are skipped.
+    ///
+    /// Acks are waited on from each recipient once it was sent to, and the message isn't done until the last
+    /// of them got their turn. Sends failing in any tick are reported to `on_send_error`, and sending again
+    /// after `with_ack_timeout` goes at the same rate.
+    pub fn with_fanout_rate(mut self, peers_per_tick: usize) -> Self {
+        self.fanout_rate = Some(peers_per_tick.max(1));
         self
     }
+
+    /// How many bytes this message takes up serialized, before compression and the other wire wrappers, or 0
+    /// if it can't be serialized, which sending it will report.
+    fn encoded_len(&self) -> usize {
+        let packet = WirePacketRef::Message {
+            id: self.id,
+            must_ack: self.wants_ack(),
+            trace_id: self.trace_id,
+            origin: self.origin,
+            exactly_once: self.delivery == Delivery::ExactlyOnce,
+            data: &self.data,
+        };
+        bincode::serialized_size(&packet).map_or(0, |size| size as usize)
+    }
+
+    /// Whether peers are asked to ack this message.
+    fn wants_ack(&self) -> bool {
+        self.ack_handler.is_some() || self.ack_deadline.is_some() || self.ack_timeout.is_some()
+    }
+}
+
+/// The recipients of a message sent with `Message::with_fanout_rate` still waiting for their turn.
+struct Fanout<A> {
+    id: MessageId,
+    channel: usize,
+    packet: Packet,
+    remaining: VecDeque<PeerId>,
+    rate: usize,
+    /// The message's `on_send_error` handler, none for a fanout sending the message again.
+    send_error_handler: Option<SendErrorHandler<A>>,
+}
+
+/// A peer confirming they got a message, by acking it. Collected for `TApp::on_receipts` once turned on with
+/// `NetworkManager::with_delivery_receipts`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeliveryReceipt {
+    pub id: MessageId,
+    pub peer: PeerId,
+    /// When the ack arrived, by the manager's clock (the sum of the deltas passed to `tick`).
+    pub acked_at: Duration,
+    /// How long after sending the ack arrived.
+    pub round_trip: Duration,
+}
+
+/// Whether peers that connect while a broadcast is waiting on acks are expected to ack it too, see
+/// `NetworkManager::with_late_join_policy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LateJoinPolicy {
+    /// The broadcast only waits on the peers it was sent to.
+    #[default]
+    Exempt,
+    /// The broadcast is sent to peers that connect while it waits, and waits on their acks as well.
+    Include,
+}
+
+/// How far along a message is in being acked, see `NetworkManager::ack_status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckStatus {
+    /// Still waiting, `acked` of the `total` peers it's waiting on have acked so far.
+    Pending { acked: usize, total: usize },
+    /// Everyone acked and the handlers have been called.
+    Complete,
+    /// Not a message we're waiting on, or it completed too long ago to remember.
+    Unknown,
 }
 
 pub struct MessageWaitingForAck<U: TUser, T: TApp<U>, M: TSerializableMessage> {
     message: Message<U, T::Application, M>,
-    peers_that_have_acked: Vec<PeerId>,
+    /// Who the message was sent to and is waiting on. Peers leave it when they disconnect, and broadcasts
+    /// gain peers that connect later under `LateJoinPolicy::Include`.
+    recipients: Vec<PeerId>,
+    /// Each peer that has acked so far, with what they responded.
+    responses: Vec<(PeerId, AckResult<M>)>,
+    /// How long the `NetworkManager` had been running when this was sent.
+    sent_at: Duration,
+    /// When this was last sent again by `Message::with_ack_timeout`, or first sent.
+    last_sent_at: Duration,
+    /// How many times this was sent again.
+    retries: u32,
+    /// Whether recipients are still waiting for their turn under `Message::with_fanout_rate`, and are added
+    /// as they get it.
+    fanning_out: bool,
 }
 
 impl<U: TUser, T: TApp<U>, M: TSerializableMessage> MessageWaitingForAck<U, T, M> {
     pub fn was_broadcast(&self) -> bool {
-        self.message.to_peer.is_none()
+        self.message.target.is_broadcast()
     }
 
-    pub fn have_all_acked(&self, connected_peers: &Vec<PeerId>) -> bool {
-        if self.was_broadcast() {
-            // Check that all currently connected peers have acked
-            connected_peers.iter().all(|peer| self.peers_that_have_acked.contains(peer))
-        } else {
-            let intended_recipient = self.message.to_peer.unwrap(); // SAFETY: safe to unwrap here because we are NOT a broadcast, which means this had to have been set.
-            self.peers_that_have_acked.contains(&intended_recipient)
-        }
+    pub fn has_acked(&self, peer: &PeerId) -> bool {
+        self.responses.iter().any(|(acked, _)| acked == peer)
+    }
+
+    pub fn have_all_acked(&self) -> bool {
+        self.recipients.iter().all(|peer| self.has_acked(peer))
+    }
+
+    /// Whether enough peers acked for a message sent with `with_quorum_ack`.
+    pub fn has_quorum(&self) -> bool {
+        self.message.quorum.is_some_and(|quorum| {
+            self.responses.iter().filter(|(_, response)| !response.is_nack()).count() >= quorum
+        })
+    }
+
+    /// Whether we're done waiting on this message: everyone acked, or enough for its quorum did.
+    pub fn is_complete(&self) -> bool {
+        self.has_quorum() || (!self.fanning_out && self.have_all_acked())
+    }
+
+    /// How many of the peers this is waiting on have acked, out of how many.
+    pub fn progress(&self) -> (usize, usize) {
+        let acked = self.recipients.iter().filter(|peer| self.has_acked(peer)).count();
+        (acked, self.recipients.len())
+    }
+
+    /// The recipients that haven't acked yet.
+    fn missing(&self) -> Vec<PeerId> {
+        self.recipients.iter().filter(|peer| !self.has_acked(peer)).copied().collect()
+    }
+
+    /// Whether the ack timeout set with `Message::with_ack_timeout` has passed since it was last sent.
+    fn is_past_ack_timeout(&self, now: Duration) -> bool {
+        self.message.ack_timeout.is_some_and(|timeout| now.saturating_sub(self.last_sent_at) >= timeout)
+    }
+
+    /// Whether the deadline set with `Message::ack_deadline` has passed as of `now`.
+    fn is_past_deadline(&self, now: Duration) -> bool {
+        self.message.ack_deadline.is_some_and(|deadline| now.saturating_sub(self.sent_at) >= deadline)
     }
 }
 
 pub struct NetworkManager<U: TUser, T: TApp<U>, M: TSerializableMessage> {
     socket: WebRtcSocket,
+    channels: Vec<ChannelInfo>,
     app: T,
     messages_waiting_for_ack: HashMap<MessageId, MessageWaitingForAck<U, T, M>>,
     next_message_id: MessageId,
+    stats: NetworkStats,
+    user_dispatch: bool,
+    manual_users: bool,
+    connected_peers: Vec<PeerId>,
+    /// The peer count `TApp::on_peer_count_changed` was last told about.
+    reported_peer_count: usize,
+    late_join_policy: LateJoinPolicy,
+    /// Peers we've kicked or timed out, we ignore them until matchbox reports them as disconnected.
+    ejected: HashSet<PeerId>,
+    /// When we last heard anything from each connected peer.
+    last_seen: HashMap<PeerId, Duration>,
+    user_timeout: Option<Duration>,
+    heartbeat_interval: Option<Duration>,
+    /// When heartbeats were last sent.
+    last_heartbeat: Duration,
+    conditions: Option<ConditionedLink>,
+    /// Total time passed in through `tick`, used as the clock for everything time based.
+    elapsed: Duration,
+    /// The step `TApp::tick` is called with when set with `with_fixed_step`.
+    fixed_step: Option<Duration>,
+    /// Time passed in through `tick` that hasn't been stepped through yet.
+    step_accumulator: Duration,
+    /// Tells the loop handed out by `attach_message_loop` to stop.
+    shutdown: Arc<ShutdownSignal>,
+    /// The peer id the signaling server assigned us, once it has.
+    local_peer_id: Option<PeerId>,
+    closed: bool,
+    /// Ids of the last `RECENTLY_ACKED_CAPACITY` messages that completed, so `ack_status` can report them.
+    recently_acked: VecDeque<MessageId>,
+    /// What went wrong since the last tick returned, for its `TickReport`.
+    tick_errors: Vec<NetworkError>,
+    /// The receipts collected this tick, `None` unless turned on with `with_delivery_receipts`.
+    receipts: Option<Vec<DeliveryReceipt>>,
+    /// Received messages waiting for `drain_received`, `None` unless turned on with `with_received_buffer`.
+    received: Option<Vec<IncomingMessage<M>>>,
+    /// The room the socket connected to, when it was created by `NetworkManagerBuilder`.
+    room_url: Option<String>,
+    /// Smoothed round trip time to each peer, measured from how long their acks take.
+    rtt: HashMap<PeerId, Duration>,
+    paused: bool,
+    /// Packets received while paused, processed in order once resumed.
+    held_incoming: Vec<(usize, PeerId, Packet)>,
+    pause_capacity: usize,
+    signer: Option<Box<dyn TSigner>>,
+    join_sender: Option<JoinSender<U, T, M>>,
+    streams: Vec<StreamFeed<M>>,
+    /// What each recently received exactly-once message was acked with, `None` if it needed no ack.
+    dedup: DedupWindow<AckResult<M>>,
+    /// Whether every message is deduplicated, not only `Delivery::ExactlyOnce` ones.
+    dedup_all: bool,
+    ack_timeout: Option<Duration>,
+    ack_retries: u32,
+    reconnect_key: Option<ReconnectKey<U>>,
+    /// The highest message id received from each logical user that disconnected, by their reconnect key.
+    reconnect_marks: HashMap<u64, MessageId>,
+    /// The socket's message loop, when we were given it to drive ourselves.
+    owned_loop: Option<ManagedMessageLoop>,
+    channel_router: Option<ChannelRouter<M>>,
+    /// Broadcasts queued with `broadcast_with`, sent after the message queue.
+    tailored_broadcasts: Vec<TailoredPayload<U, M>>,
+    outgoing_streams: Vec<OutgoingStream>,
+    fanouts: Vec<Fanout<T::Application>>,
+    next_stream_id: StreamId,
+    stream_chunk_size: usize,
+    fragment_threshold: Option<usize>,
+    compression: Compression,
+    next_fragment_group: u64,
+    reassembly: Reassembly,
+    reassembly_timeout: Duration,
+    sequenced_channels: HashSet<usize>,
+    sequence_timeout: Duration,
+    /// The number of the next packet to each peer on each sequenced channel.
+    next_seq: HashMap<(PeerId, usize), u64>,
+    sequencer: Sequencer,
+    /// Messages for a peer whose channel wasn't open yet, with the peer and when we started holding them.
+    pending_sends: Vec<(PeerId, Duration, Message<U, T, M>)>,
+    connect_timeout: Duration,
+    hello: Option<Vec<u8>>,
+    /// Peers we're waiting on a hello from before they get a user, with when they connected.
+    awaiting_hello: HashMap<PeerId, Duration>,
+    /// Peers that left within the rejoin window, with when they left.
+    departed: HashMap<PeerId, Duration>,
+    /// When peers that left did, by their reconnect key, for recognising them under a new peer id.
+    departed_keys: HashMap<u64, Duration>,
+    /// The users of peers that left within the rejoin window, with when, so `migrate_user` can still move them.
+    departed_users: HashMap<PeerId, (Duration, U)>,
+    rejoin_window: Duration,
+    log_sink: Option<LogSink>,
+    name: Option<String>,
     _phantom_data: PhantomData<(U, M)>,
 }
 
+impl<U: TUser, T: TApp<U>, M: TSerializableMessage> Drop for NetworkManager<U, T, M> {
+    fn drop(&mut self) {
+        self.shutdown.stop();
+        for stream in &self.streams {
+            stream.close();
+        }
+    }
+}
+
 impl<U: TUser, T: TApp<U>, M> NetworkManager<U, T, M>
 where
     T: TApp<U, Application = T, Message = M>,
@@ -148,138 +983,2794 @@ where
 {
     pub fn new(socket: WebRtcSocket, app: T) -> Self {
         Self {
+            channels: ChannelInfo::collect(&socket),
             socket,
             app,
             messages_waiting_for_ack: HashMap::new(),
             next_message_id: 0,
+            stats: NetworkStats::default(),
+            user_dispatch: false,
+            manual_users: false,
+            connected_peers: Vec::new(),
+            reported_peer_count: 0,
+            late_join_policy: LateJoinPolicy::Exempt,
+            ejected: HashSet::new(),
+            last_seen: HashMap::new(),
+            user_timeout: None,
+            heartbeat_interval: None,
+            last_heartbeat: Duration::ZERO,
+            conditions: None,
+            elapsed: Duration::ZERO,
+            fixed_step: None,
+            step_accumulator: Duration::ZERO,
+            shutdown: Arc::new(ShutdownSignal::default()),
+            local_peer_id: None,
+            closed: false,
+            recently_acked: VecDeque::new(),
+            tick_errors: Vec::new(),
+            receipts: None,
+            received: None,
+            room_url: None,
+            rtt: HashMap::new(),
+            paused: false,
+            held_incoming: Vec::new(),
+            pause_capacity: DEFAULT_PAUSE_CAPACITY,
+            signer: None,
+            join_sender: None,
+            streams: Vec::new(),
+            dedup: DedupWindow::new(DEFAULT_DEDUP_WINDOW),
+            dedup_all: false,
+            ack_timeout: None,
+            ack_retries: DEFAULT_ACK_RETRIES,
+            reconnect_key: None,
+            reconnect_marks: HashMap::new(),
+            owned_loop: None,
+            channel_router: None,
+            tailored_broadcasts: Vec::new(),
+            outgoing_streams: Vec::new(),
+            fanouts: Vec::new(),
+            next_stream_id: 0,
+            stream_chunk_size: DEFAULT_STREAM_CHUNK_SIZE,
+            fragment_threshold: None,
+            compression: Compression::default(),
+            next_fragment_group: 0,
+            reassembly: Reassembly::default(),
+            reassembly_timeout: DEFAULT_REASSEMBLY_TIMEOUT,
+            sequenced_channels: HashSet::new(),
+            sequence_timeout: DEFAULT_SEQUENCE_TIMEOUT,
+            next_seq: HashMap::new(),
+            sequencer: Sequencer::default(),
+            pending_sends: Vec::new(),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            hello: None,
+            awaiting_hello: HashMap::new(),
+            departed: HashMap::new(),
+            departed_keys: HashMap::new(),
+            departed_users: HashMap::new(),
+            rejoin_window: DEFAULT_REJOIN_WINDOW,
+            log_sink: None,
+            name: None,
             _phantom_data: PhantomData,
         }
     }
 
-    pub fn tick(&mut self, delta: Duration) {
-        for (peer_id, state) in self.socket.update_peers() {
-            match state {
-                PeerState::Connected => {
-                    let users = self.app.get_users_mut();
-                    let user = U::new(peer_id);
-                    users.insert(peer_id, user);
-                    self.app.post_user_connected(peer_id);
-                    info!("Peer connected: {peer_id}");
-                }
-                PeerState::Disconnected => {
-                    info!("Peer disconnected: {peer_id}");
-                    match self.app.get_users_mut().remove(&peer_id){
-                        Some(_) => self.app.post_user_disconnected(peer_id),
-                        None => warn!("Peer disconnected but no user found"),
-                    }
-                }
-            }
-        }
-
-        let connected_peers: Vec<_> = self.socket.connected_peers().collect();
-
-        // Accept any messages incoming
-        for (from_peer, packet) in self.socket.channel_mut(CHANNEL_ID).receive() {
+    pub(crate) fn set_room_url(&mut self, room_url: String) {
+        self.room_url = Some(room_url);
+    }
 
-            let incoming_message: PackedMessage<M> = match bincode::deserialize_from(&packet[..]) {
-                Ok(packet) => packet,
-                Err(e) => {
-                    warn!("Failed to deserialize packet: {e}");
-                    continue;
-                }
-            };
+    /// The room url the socket connected to. Only known when the manager was set up with
+    /// `NetworkManagerBuilder::connect`, for a socket passed to `new` it's `None`.
+    pub fn room_url(&self) -> Option<&str> {
+        self.room_url.as_deref()
+    }
 
-            // Is this message an ack?
-            if incoming_message.is_ack {
-                if let Some(unacked) = self.messages_waiting_for_ack.get_mut(&incoming_message.id) {
-                    unacked.peers_that_have_acked.push(from_peer);
-
-                    // If all peers have acked, call the handler(s)
-                    if unacked.have_all_acked(&connected_peers) {
-                        // For broadcasted messages, call the handler on all peers
-                        if unacked.was_broadcast() {
-                            for peer in connected_peers.iter() {
-                                if let Some(handler) = unacked.message.ack_handler.as_mut() {
-                                    handler(&mut self.app, incoming_message.id, *peer, &incoming_message.data);
-                                }
-                            }
-                        } else {
-                            if let Some(handler) = unacked.message.ack_handler.as_mut() {
-                                handler(&mut self.app, incoming_message.id, from_peer, &incoming_message.data);
-                            }
-                        }
+    /// Hands the socket's message loop future over to the manager and returns it wrapped, to be driven in
+    /// its place. The returned future resolves once this manager is closed or dropped, so the loop doesn't
+    /// keep running in the background after we're done with the socket.
+    pub fn attach_message_loop(&mut self, message_loop: MessageLoopFuture) -> ManagedMessageLoop {
+        ManagedMessageLoop::new(message_loop, self.shutdown.clone())
+    }
 
-                        // Clean up after handling
-                        self.messages_waiting_for_ack.remove(&incoming_message.id);
-                    }
-                }
-            } else {
-                if incoming_message.must_ack {
-                    let response = self.app.receive_must_ack(incoming_message.id, from_peer, &incoming_message.data);
+    /// Hands the socket's message loop future to the manager to drive itself, for runtimes where nobody else
+    /// polls it. It's then advanced at the start of every `tick`, so the newest packets are read, and again
+    /// at the end, so what was sent leaves right away. It's stopped when the manager is closed or dropped.
+    pub fn own_message_loop(&mut self, message_loop: MessageLoopFuture) {
+        self.owned_loop = Some(self.attach_message_loop(message_loop));
+    }
 
-                    // send the response
-                    let packet = match bincode::serialize(&PackedMessage {
-                        id: incoming_message.id,
-                        data: response,
-                        is_ack: true,
-                        must_ack: false,
-                    }) {
-                        Ok(packet) => packet,
-                        Err(e) => {
-                            warn!("Failed to serialize packet: {e}");
-                            continue;
-                        }
-                    }.into_boxed_slice();
+    /// Advances the message loop given to `own_message_loop` as far as it can go without waiting, flushing
+    /// sends to the wire and reading whatever arrived. `tick` already does this, but it can be called more
+    /// often for lower latency. Returns whether the loop is still running.
+    pub fn drive(&mut self) -> bool {
+        let Some(message_loop) = self.owned_loop.as_mut() else {
+            return false;
+        };
 
-                    self.socket.channel_mut(CHANNEL_ID).send(packet, from_peer);
-                }
-                else {
-                    self.app.receive(incoming_message.id, from_peer, &incoming_message.data);
+        let mut cx = Context::from_waker(noop_waker_ref());
+        match Pin::new(message_loop).poll(&mut cx) {
+            Poll::Pending => true,
+            Poll::Ready(result) => {
+                match result {
+                    Ok(()) => net_log!(self, Level::Info, "Message loop finished"),
+                    Err(e) => net_log!(self, Level::Warn, "Message loop failed: {e}"),
                 }
+                self.owned_loop = None;
+                false
             }
         }
+    }
 
-        // Send any messages waiting to be sent
-        for mut message in self.app.message_queue().drain(..) {
+    /// Simulates a degraded network by delaying and dropping packets in both directions, for testing how the
+    /// application copes with latency and loss. Like on a real network, packets are only lost on unreliable
+    /// channels and only reordered on unordered ones.
+    pub fn with_network_conditions(mut self, conditions: NetworkConditions) -> Self {
+        self.conditions = Some(ConditionedLink::new(conditions));
+        self
+    }
 
-            message.id = self.next_message_id;
+    /// Removes users that we haven't received anything from for longer than `timeout`, independently of
+    /// matchbox noticing the connection dropped. See `sweep_timeouts`.
+    pub fn with_user_timeout(mut self, timeout: Duration) -> Self {
+        self.user_timeout = Some(timeout);
+        self
+    }
 
-            let packet = match bincode::serialize(&PackedMessage {
-                id: message.id,
-                data: message.data.clone(),
-                is_ack: false,
-                must_ack: message.ack_handler.is_some(),
-            }) {
-                Ok(packet) => packet,
-                Err(e) => {
-                    warn!("Failed to serialize packet: {e}");
-                    continue;
-                }
-            }.into_boxed_slice();
+    /// Splits packets bigger than `threshold` bytes into fragments that fit, which the receiving manager puts
+    /// back together before handling the packet, for messages too big for a single data channel message, e.g.
+    /// a full world snapshot. Fragments may arrive in any order. Every peer needs to run a version that
+    /// understands fragments.
+    ///
+    /// When a fragment sent on an unreliable channel is lost the whole packet is, and incomplete packets
+    /// from a peer are dropped when they disconnect. For very large transfers `send_stream` is gentler on the
+    /// connection. Thresholds below `MIN_FRAGMENT_THRESHOLD` are raised to it, and receivers only put back
+    /// packets up to their `with_max_reassembly_bytes`.
+    pub fn with_fragment_threshold(mut self, threshold: usize) -> Self {
+        self.fragment_threshold = Some(threshold.max(MIN_FRAGMENT_THRESHOLD));
+        self
+    }
 
-            match message.to_peer {
-                Some(to_peer) => {
-                    self.socket.channel_mut(CHANNEL_ID).send(packet, to_peer);
-                }
-                None => {
-                    // Broadcast to all connected peers
-                    for &peer in &connected_peers {
-                        self.socket.channel_mut(CHANNEL_ID).send(packet.clone(), peer);
-                    }
-                }
-            }
+    /// How many bytes of incomplete fragmented packets are held per peer, `DEFAULT_MAX_REASSEMBLY_BYTES` unless
+    /// set. The oldest incomplete packet is dropped to make room, and packets bigger than this are never put
+    /// back together. Applies whether or not we fragment anything ourselves.
+    pub fn with_max_reassembly_bytes(mut self, max_bytes: usize) -> Self {
+        self.reassembly = Reassembly::new(max_bytes);
+        self
+    }
 
-            let id = self.next_message_id;
-            self.next_message_id += 1;
+    /// How long an incomplete fragmented packet waits for the rest of its fragments before it's dropped.
+    pub fn with_reassembly_timeout(mut self, timeout: Duration) -> Self {
+        self.reassembly_timeout = timeout;
+        self
+    }
 
-            if message.ack_handler.is_some() {
-                self.messages_waiting_for_ack.insert(id,MessageWaitingForAck {
-                    message,
-                    peers_that_have_acked: Vec::new(),
+    /// Sets the order messages received on `channel` are handled in. With `ChannelOrdering::Sequenced`
+    /// everything we send on the channel is numbered, and the receiving manager handles it in the order it was
+    /// sent, see `ChannelOrdering::Sequenced` for how reliable and unreliable channels differ. Every peer should
+    /// use the same ordering for a channel.
+    ///
+    /// Ordering is per sender. When a packet on a reliable channel is held up anyway, e.g. by a peer running
+    /// out of buffer, those behind it are released after the timeout set with `with_sequence_timeout`.
+    pub fn with_channel_ordering(mut self, channel: usize, ordering: ChannelOrdering) -> Self {
+        match ordering {
+            ChannelOrdering::Arrival => self.sequenced_channels.remove(&channel),
+            ChannelOrdering::Sequenced => self.sequenced_channels.insert(channel),
+        };
+        self
+    }
+
+    /// How long messages on a reliable `ChannelOrdering::Sequenced` channel are held back waiting on one sent
+    /// before them, before giving up on it.
+    pub fn with_sequence_timeout(mut self, timeout: Duration) -> Self {
+        self.sequence_timeout = timeout;
+        self
+    }
+
+    /// Compresses every packet we send with `compression`, worth it for big messages like serialized game
+    /// state on slow connections. Peers don't need the same setting, but do need a version that reads the
+    /// compression header, built with the feature for the codec.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Sends every connected peer a small heartbeat packet each `interval`, and removes peers we haven't
+    /// received anything from, heartbeats included, within `timeout` as with `with_user_timeout`. Catches
+    /// peers whose app stopped responding while WebRTC keeps the connection up. Peers running without
+    /// heartbeats time out if they're quiet for longer than `timeout`, so turn it on for everyone.
+    pub fn with_heartbeat(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.heartbeat_interval = Some(interval);
+        self.user_timeout = Some(timeout);
+        self
+    }
+
+    /// Delivers messages from peers that have a user through `TApp::receive_from_user`, handing it the
+    /// sender's user alongside the message.
+    pub fn with_user_dispatch(mut self) -> Self {
+        self.user_dispatch = true;
+        self
+    }
+
+    /// Leaves the `UserList` to the app: peers connecting and disconnecting no longer add and remove users or
+    /// fire the user hooks, only `TApp::on_peer_state_changed`. Insert users with `UserList::insert` once your
+    /// own membership logic admits them, and remove them with `UserList::remove`.
+    pub fn with_manual_users(mut self) -> Self {
+        self.manual_users = true;
+        self
+    }
+
+    /// How many bytes each chunk of a stream started with `send_stream` carries.
+    pub fn with_stream_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.stream_chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// How many received packets are held on to while paused, anything arriving after that is dropped.
+    pub fn with_pause_capacity(mut self, capacity: usize) -> Self {
+        self.pause_capacity = capacity;
+        self
+    }
+
+    /// Signs everything we send and rejects anything received that isn't signed correctly, see `TSigner`.
+    /// Every peer in the room has to use a compatible signer.
+    pub fn with_signer(mut self, signer: impl TSigner + 'static) -> Self {
+        self.signer = Some(Box::new(signer));
+        self
+    }
+
+    /// The `Message::with_ack_timeout` of messages waiting on acks that didn't set their own, so every
+    /// message with an ack handler or deadline is sent again when its acks don't show up. None by default.
+    pub fn with_ack_timeout(mut self, timeout: Duration) -> Self {
+        self.ack_timeout = Some(timeout);
+        self
+    }
+
+    /// The `Message::with_ack_retries` of messages that didn't set their own, `DEFAULT_ACK_RETRIES` unless
+    /// set.
+    pub fn with_ack_retries(mut self, retries: u32) -> Self {
+        self.ack_retries = retries;
+        self
+    }
+
+    /// Introduces us to every peer that connects with `meta`, e.g. a bincode encoded name and team, and holds
+    /// off creating the users of peers until their own hello arrives, so users are created with their
+    /// metadata by `TApp::create_user_with_meta` before `post_user_connected`. Every peer in the room should
+    /// set a hello. A peer whose hello doesn't arrive within the timeout set with `with_connect_timeout` gets a
+    /// user from `TApp::create_user` instead.
+    pub fn with_hello(mut self, meta: Vec<u8>) -> Self {
+        self.hello = Some(meta);
+        self
+    }
+
+    /// How long messages to a peer whose channel isn't open yet are held for them. If they still aren't
+    /// connected after that, `TApp::on_connect_failed` fires and every message held for them is dropped with
+    /// `SendError::NoSuchPeer`. Messages to a peer that left are dropped with it straight away.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Keeps received messages for `drain_received` instead of handing them to `TApp::receive` one at a time,
+    /// for apps that process a tick's input in bulk. Messages that must be acked are kept too, and acked with
+    /// what `TApp::ack_response` returns for them right away, as `ReceiveOutcome::Handled`.
+    pub fn with_received_buffer(mut self) -> Self {
+        self.received = Some(Vec::new());
+        self
+    }
+
+    /// Moves every message received since the last call into `out`, in the order they arrived. Only collects
+    /// anything when turned on with `with_received_buffer`. Call it after each `tick`, the buffer grows until
+    /// drained.
+    pub fn drain_received(&mut self, out: &mut Vec<IncomingMessage<M>>) {
+        if let Some(received) = self.received.as_mut() {
+            out.append(received);
+        }
+    }
+
+    /// Collects a `DeliveryReceipt` for every ack that arrives and hands a tick's worth of them to
+    /// `TApp::on_receipts` at once, for computing delivery rates and latencies in aggregate.
+    pub fn with_delivery_receipts(mut self) -> Self {
+        self.receipts = Some(Vec::new());
+        self
+    }
+
+    /// How long peers that left are remembered, so that one connecting again within it is reported to
+    /// `TApp::on_peer_rejoined`, e.g. after the signaling server had a hiccup and the mesh split for a while.
+    /// Peers are recognised by the key set with `with_reconnect_dedup` if there is one, or else by their peer
+    /// id. Their users are kept as long, for `migrate_user`.
+    pub fn with_rejoin_window(mut self, window: Duration) -> Self {
+        self.rejoin_window = window;
+        self
+    }
+
+    /// Picks whether broadcasts still waiting on acks are sent to peers that connect in the meantime and wait
+    /// on them too, see `LateJoinPolicy`. By default they only wait on the peers they were sent to.
+    /// `TApp::on_ack_target_changed` reports every change to who a message waits on.
+    pub fn with_late_join_policy(mut self, policy: LateJoinPolicy) -> Self {
+        self.late_join_policy = policy;
+        self
+    }
+
+    /// Calls `TApp::tick` with a fixed `step` instead of the delta passed to `tick`, for simulations that need
+    /// a constant timestep while the network is polled as often as possible. Time passed in is accumulated,
+    /// and each `tick` calls `TApp::tick` as many times as whole steps fit, which can be none. Everything else,
+    /// `post_tick` included, still runs once per call. A zero step turns it off again.
+    pub fn with_fixed_step(mut self, step: Duration) -> Self {
+        self.fixed_step = (!step.is_zero()).then_some(step);
+        self.step_accumulator = Duration::ZERO;
+        self
+    }
+
+    /// How far into the next fixed step the accumulated time is, from 0 to 1, for interpolating rendering
+    /// between steps. Always 0 without `with_fixed_step`.
+    pub fn step_progress(&self) -> f32 {
+        self.fixed_step.map_or(0.0, |step| self.step_accumulator.as_secs_f32() / step.as_secs_f32())
+    }
+
+    /// Names this manager, e.g. after its room, so its log output can be told apart from other managers in the
+    /// same process. Every line it logs is prefixed with the name in brackets.
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// The name set with `with_name`.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Sends the manager's diagnostics to `sink` instead of the `log` crate, for environments without a global
+    /// logger such as some embedded or wasm setups. Filter on the level inside the sink if needed.
+    pub fn with_log_sink(mut self, sink: impl Fn(Level, &str) + 'static) -> Self {
+        self.log_sink = Some(Box::new(sink));
+        self
+    }
+
+    /// How many recent message ids are remembered per peer to drop repeats of `Delivery::ExactlyOnce`
+    /// messages. A repeat arriving after more messages than that is processed again.
+    pub fn with_dedup_window(mut self, capacity: usize) -> Self {
+        self.dedup = DedupWindow::new(capacity);
+        self
+    }
+
+    /// Drops repeats of every message received, as if they were all sent with `Delivery::ExactlyOnce`, so
+    /// retransmissions and duplicates on unreliable channels never reach `TApp::receive` twice, whatever the
+    /// sender asked for. How many ids are remembered is set with `with_dedup_window`.
+    pub fn with_dedup_all(mut self) -> Self {
+        self.dedup_all = true;
+        self
+    }
+
+    /// Carries `Delivery::ExactlyOnce` dedup over when a peer reconnects under a new peer id. `key` names the
+    /// logical user behind a peer, e.g. an account id. When a peer with a key disconnects, the highest message
+    /// id received from them is kept, and a peer connecting with the same key has every exactly-once message
+    /// up to it dropped as already applied. Users moved with `migrate_user` carry it over without a key.
+    ///
+    /// Message ids only keep counting up while the sender's manager lives, so a peer that restarts starts
+    /// over below the mark and would have its messages dropped. Make the key change with the sender's
+    /// session, e.g. by including a session id, if that can happen.
+    pub fn with_reconnect_dedup<K: Hash>(mut self, key: impl Fn(&PeerId, &U) -> Option<K> + 'static) -> Self {
+        self.reconnect_key = Some(Box::new(move |peer, user| {
+            key(peer, user).map(|key| {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                hasher.finish()
+            })
+        }));
+        self
+    }
+
+    /// Routes messages to channels by what they carry, so send sites don't have to pick one, e.g. chat on the
+    /// reliable channel and positions on the unreliable one:
+    ///
+    /// ```rust,ignore
+    /// network.with_channel_router(|message| match message {
+    ///     MyMessage::Chat(_) => Some(0),
+    ///     MyMessage::Position(_) => Some(1),
+    /// })
+    /// ```
+    ///
+    /// Messages built with `ordered` keep going where that puts them. Indices of channels the socket
+    /// doesn't have fall back to the default channel.
+    pub fn with_channel_router(mut self, router: impl Fn(&M) -> Option<usize> + 'static) -> Self {
+        self.channel_router = Some(Box::new(router));
+        self
+    }
+
+    /// A stream of every message received from now on, for awaiting inbound traffic in async code. Messages
+    /// still go to the app as usual. See `MessageStream`.
+    pub fn incoming(&mut self) -> MessageStream<M> {
+        let (feed, stream) = StreamFeed::new();
+        self.streams.push(feed);
+        stream
+    }
+
+    /// Registers what to send each peer as they connect, e.g. the current state of the world. The closure runs
+    /// right after `post_user_connected`, and the message it returns is sent to the new peer only.
+    pub fn on_join_send(mut self, sender: impl FnMut(&mut T, PeerId) -> Option<Message<U, T, M>> + 'static) -> Self {
+        self.join_sender = Some(Box::new(sender));
+        self
+    }
+
+    pub fn app(&self) -> &T {
+        &self.app
+    }
+
+    pub fn app_mut(&mut self) -> &mut T {
+        &mut self.app
+    }
+
+    /// The data channels the socket was configured with, in index order.
+    pub fn channels(&self) -> &[ChannelInfo] {
+        &self.channels
+    }
+
+    /// How many received messages `TApp::receive` reported as unhandled.
+    pub fn unhandled_count(&self) -> u64 {
+        self.stats.unhandled
+    }
+
+    /// How many bytes `data` takes up serialized as a message, before it's compressed, signed, sequenced or
+    /// split into fragments on the way out, e.g. to keep messages under the fragment threshold. It's measured
+    /// without serializing into a buffer. What ends up on the wire can differ both ways: compression shrinks
+    /// it, while a signature, the sequence number and fragment headers each add a few bytes.
+    pub fn payload_size(&self, data: &M) -> Result<usize, SendError> {
+        let packet = WirePacketRef::Message {
+            id: self.next_message_id,
+            must_ack: false,
+            trace_id: None,
+            origin: None,
+            exactly_once: false,
+            data,
+        };
+        bincode::serialized_size(&packet)
+            .map(|size| size as usize)
+            .map_err(|e| SendError::Serialize(e.to_string()))
+    }
+
+    pub fn stats(&self) -> &NetworkStats {
+        &self.stats
+    }
+
+    /// The traffic to and from `peer`, while they're connected. See `NetworkStats::peers`.
+    pub fn peer_stats(&self, peer: PeerId) -> Option<&PeerStats> {
+        self.stats.peers.get(&peer)
+    }
+
+    /// How many bytes the messages in the app's queue take up on the wire, see `MessageQueue::pending_bytes`.
+    pub fn pending_bytes(&mut self) -> usize {
+        self.app.message_queue().pending_bytes()
+    }
+
+    /// Makes the app's queue refuse messages once it holds `max_bytes`, see `MessageQueue::with_max_bytes`.
+    pub fn with_max_pending_bytes(mut self, max_bytes: usize) -> Self {
+        self.app.message_queue().set_max_bytes(Some(max_bytes));
+        self
+    }
+
+    /// Writes a human-readable snapshot of the manager's state, handy to attach to bug reports.
+    ///
+    /// Ages and uptime are measured with the deltas passed into `tick`.
+    pub fn dump_state(&mut self, mut w: impl Write) -> io::Result<()> {
+        match &self.name {
+            Some(name) => writeln!(w, "NetworkManager state ({name})")?,
+            None => writeln!(w, "NetworkManager state")?,
+        }
+        writeln!(w, "  uptime: {:?}", self.elapsed)?;
+        writeln!(w, "  status: {:?}", self.connection_status())?;
+        if let Some(room_url) = &self.room_url {
+            writeln!(w, "  room: {room_url}")?;
+        }
+        match self.socket.id() {
+            Some(local_peer) => writeln!(w, "  local peer: {local_peer}")?,
+            None => writeln!(w, "  local peer: not assigned yet")?,
+        }
+
+        writeln!(w, "  connected peers ({}):", self.connected_peers.len())?;
+        for peer in &self.connected_peers {
+            writeln!(w, "    {peer}")?;
+        }
+
+        let queue = self.app.message_queue();
+        writeln!(w, "  queued messages: {} ({} bytes)", queue.len(), queue.pending_bytes())?;
+        writeln!(
+            w,
+            "  sent {} message(s) in {} bytes, received {} message(s) in {} bytes",
+            self.stats.messages_sent, self.stats.bytes_sent, self.stats.messages_received, self.stats.bytes_received,
+        )?;
+        writeln!(w, "  unhandled messages: {}", self.stats.unhandled)?;
+        writeln!(w, "  orphan acks: {}", self.stats.orphan_acks)?;
+        if self.paused {
+            writeln!(w, "  paused, holding {} packet(s)", self.held_incoming.len())?;
+        }
+        if !self.pending_sends.is_empty() {
+            writeln!(w, "  holding {} message(s) for peers that aren't ready", self.pending_sends.len())?;
+        }
+        if !self.outgoing_streams.is_empty() {
+            writeln!(w, "  sending {} stream(s)", self.outgoing_streams.len())?;
+        }
+        let dropped = &self.stats.dropped;
+        writeln!(
+            w,
+            "  dropped messages: {} (serialize error {}, no such peer {}, buffer full {}, channel closed {})",
+            dropped.total(),
+            dropped.serialize_error,
+            dropped.no_such_peer,
+            dropped.buffer_full,
+            dropped.channel_closed,
+        )?;
+
+        let mut pending: Vec<_> = self.messages_waiting_for_ack.iter().collect();
+        pending.sort_by_key(|(id, _)| **id);
+        writeln!(w, "  pending acks ({}):", pending.len())?;
+        for (id, unacked) in pending {
+            let to = match unacked.message.target {
+                Target::Broadcast => "all peers".to_string(),
+                Target::Peer(peer) => peer.to_string(),
+                Target::Where(_) => format!("{} filtered peer(s)", unacked.recipients.len()),
+                Target::Peers(_) => format!("{} listed peer(s)", unacked.recipients.len()),
+                Target::Best(_) => format!("{} lowest latency peer(s)", unacked.recipients.len()),
+                Target::AllExcept(peer) => format!("all peers but {peer}"),
+                Target::Random { .. } => format!("{} random peer(s)", unacked.recipients.len()),
+            };
+            writeln!(
+                w,
+                "    #{id} to {to}, acked by {}, age {:?}",
+                unacked.responses.len(),
+                self.elapsed.saturating_sub(unacked.sent_at),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Where message `id` is in being acked by its recipients, for polling progress instead of waiting on the
+    /// ack handler, e.g. to show how many players are ready. Only the most recently completed messages are
+    /// remembered as `Complete`, older ones are `Unknown`.
+    pub fn ack_status(&self, id: MessageId) -> AckStatus {
+        if let Some(unacked) = self.messages_waiting_for_ack.get(&id) {
+            let (acked, total) = unacked.progress();
+            return AckStatus::Pending { acked, total };
+        }
+        match self.recently_acked.contains(&id) {
+            true => AckStatus::Complete,
+            false => AckStatus::Unknown,
+        }
+    }
+
+    /// Saves which peers have users and the messages still waiting for acks, along with `app_state`, into a
+    /// blob to persist for crash recovery or to send to a peer joining late. See `restore`.
+    pub fn snapshot<S: serde::Serialize>(&mut self, app_state: S) -> Result<Vec<u8>, SnapshotError> {
+        let mut peers: Vec<PeerId> = self.app.get_users_mut().keys().copied().collect();
+        peers.sort();
+
+        let mut pending_acks: Vec<_> = self.messages_waiting_for_ack.iter()
+            .map(|(id, unacked)| PendingAckSnapshot {
+                id: *id,
+                recipients: unacked.recipients.clone(),
+                acked: unacked.responses.iter().map(|(peer, _)| *peer).collect(),
+                data: unacked.message.data.clone(),
+            })
+            .collect();
+        pending_acks.sort_by_key(|pending| pending.id);
+
+        bincode::serialize(&NetworkSnapshot { peers, pending_acks, app_state })
+            .map_err(|e| SnapshotError(e.to_string()))
+    }
+
+    /// Loads a blob made by `snapshot`. Peers in it that are connected but have no user get one, through
+    /// `TApp::create_user` and `post_user_connected` as usual. Messages that were waiting for acks aren't
+    /// resent, they're returned with the app's state for the app to decide, see `resume_pending_ack`.
+    pub fn restore<S: for<'de> serde::Deserialize<'de>>(&mut self, blob: &[u8]) -> Result<NetworkSnapshot<S, M>, SnapshotError> {
+        let snapshot: NetworkSnapshot<S, M> = bincode::deserialize(blob)
+            .map_err(|e| SnapshotError(e.to_string()))?;
+
+        for peer in snapshot.peers.iter().copied() {
+            if !self.connected_peers.contains(&peer) || self.app.get_users_mut().get(&peer).is_some() {
+                continue;
+            }
+            let user = self.app.create_user(peer);
+            self.app.get_users_mut().insert(peer, user);
+            self.last_seen.insert(peer, self.elapsed);
+            self.app.post_user_connected(peer);
+        }
+        Ok(snapshot)
+    }
+
+    /// Sends a message that was still waiting for acks in a snapshot again, to the recipients that hadn't acked
+    /// it, with `handler` in place of the handler lost along with the old process. Match on `pending.id` to
+    /// pick the handler that belongs to it.
+    ///
+    /// The message goes out with a new id, so a recipient whose ack was lost processes it a second time.
+    /// Recipients are looked up by the peer ids saved in the snapshot, those not connected with a user
+    /// anymore are skipped.
+    pub fn resume_pending_ack(
+        &mut self,
+        pending: PendingAckSnapshot<M>,
+        handler: impl FnMut(&mut T::Application, MessageId, FromPeerId, &AckResult<M>) + 'static
+    ) -> Result<MessageHandle, MessageError> {
+        let remaining = pending.remaining();
+        net_log!(self, Level::Info, "Resending message {} to {} peer(s) that hadn't acked it", pending.id, remaining.len());
+        let message = Message::new(pending.data)
+            .to_peers_where(move |peer, _| remaining.contains(peer))
+            .with_ack_handler(handler);
+        self.app.message_queue().enqueue(message)
+    }
+
+    /// Closes the socket, disconnecting from every peer.
+    ///
+    /// Everything in the message queue is sent first, see `flush`, and every peer is told we're leaving, so
+    /// their manager fires `post_user_disconnected` with `DisconnectReason::Left` right away instead of
+    /// waiting for matchbox or a timeout to notice. Both only reach peers if the message loop runs before
+    /// the socket shuts down, which is the case when it's owned (see `own_message_loop`).
+    ///
+    /// Messages still waiting for an ack are dropped without their handlers being called, including those
+    /// just flushed, use `disconnect_graceful` to give them a chance to complete first. So are messages held
+    /// for peers that hadn't finished connecting, streams and the rest of spread out broadcasts. A loop
+    /// attached with `attach_message_loop` resolves the next time it's polled.
+    pub fn close(&mut self) {
+        if !self.closed {
+            self.flush();
+            for peer in self.connected_peers.clone() {
+                self.send_goodbye(peer);
+            }
+            if self.owned_loop.is_some() {
+                self.drive();
+            }
+        }
+        self.closed = true;
+        self.socket.close();
+        self.messages_waiting_for_ack.clear();
+        self.held_incoming.clear();
+        self.pending_sends.clear();
+        self.outgoing_streams.clear();
+        self.fanouts.clear();
+        self.shutdown.stop();
+        for stream in self.streams.drain(..) {
+            stream.close();
+        }
+    }
+
+    /// Keeps ticking until every message waiting for an ack has been acked or `timeout` has passed, then
+    /// closes the socket. Resolves to `true` if nothing was left waiting.
+    ///
+    /// The socket's message loop future must keep being driven while this runs, otherwise nothing is
+    /// actually sent or received.
+    pub async fn disconnect_graceful(&mut self, timeout: Duration) -> bool {
+        let mut waited = Duration::ZERO;
+        while !self.messages_waiting_for_ack.is_empty() && waited < timeout {
+            if self.tick(GRACEFUL_TICK_INTERVAL).is_err() {
+                break;
+            }
+            Delay::new(GRACEFUL_TICK_INTERVAL).await;
+            waited += GRACEFUL_TICK_INTERVAL;
+        }
+
+        let all_acked = self.messages_waiting_for_ack.is_empty();
+        if !all_acked {
+            net_log!(self, Level::Warn, "Closing with {} message(s) still waiting for an ack", self.messages_waiting_for_ack.len());
+        }
+        self.close();
+        all_acked
+    }
+
+    /// Where we are in connecting to the signaling server, as of the last tick, for showing connection state
+    /// without racing the message loop future.
+    ///
+    /// The loop ending is only noticed when it was handed to `attach_message_loop` or `own_message_loop`, a
+    /// plain loop future driven elsewhere keeps this `Connected` after it ends.
+    pub fn connection_status(&self) -> ConnectionStatus {
+        match self.shutdown.loop_outcome() {
+            LoopOutcome::Failed if !self.closed => ConnectionStatus::Failed,
+            LoopOutcome::Finished | LoopOutcome::Failed => ConnectionStatus::Disconnected,
+            LoopOutcome::Running if self.closed => ConnectionStatus::Disconnected,
+            LoopOutcome::Running if self.local_peer_id.is_some() => ConnectionStatus::Connected,
+            LoopOutcome::Running => ConnectionStatus::Connecting,
+        }
+    }
+
+    /// Whether we're registered with the signaling server, see `connection_status`.
+    pub fn is_connected(&self) -> bool {
+        self.connection_status() == ConnectionStatus::Connected
+    }
+
+    /// The peers connected as of the last tick, not counting kicked and rejected ones.
+    pub fn connected_peers(&self) -> &[PeerId] {
+        &self.connected_peers
+    }
+
+    /// Our own peer id, `None` until the signaling server assigned it, as of the last tick.
+    pub fn local_peer_id(&self) -> Option<PeerId> {
+        self.local_peer_id
+    }
+
+    /// The smoothed round trip time to `peer`, measured from how long their acks take to arrive after sending,
+    /// for e.g. lag compensation. `None` until they acked something. Timed with the deltas passed to `tick`, so
+    /// it's only as precise as the tick rate.
+    pub fn rtt(&self, peer: PeerId) -> Option<Duration> {
+        self.rtt.get(&peer).copied()
+    }
+
+    /// How many peers are connected as of the last tick, see `connected_peers`.
+    pub fn peer_count(&self) -> usize {
+        self.connected_peers.len()
+    }
+
+    /// Whether a message sent to `peer` on `channel` right now would actually go out: the peer is connected
+    /// and the channel exists and is open. Messages to a single peer are held until this is true.
+    pub fn channel_ready(&self, channel: usize, peer: PeerId) -> bool {
+        let open = match self.socket.get_channel(channel) {
+            Ok(channel) => !channel.is_closed(),
+            Err(_) => false,
+        };
+        open && self.connected_peers.contains(&peer)
+    }
+
+    /// Queues `data`, received with `ctx`, to be passed on to every connected peer but the one it came from.
+    /// See `Message::relay`.
+    pub fn relay(&mut self, ctx: &ReceiveContext, data: M) -> Result<MessageHandle, MessageError> {
+        self.app.message_queue().enqueue(Message::relay(ctx, data))
+    }
+
+    /// Sends everything in the message queue right away instead of waiting for the next tick, e.g. at a
+    /// checkpoint before showing "saved", and returns how many messages were sent. Like in `tick`, the
+    /// messages are handed to matchbox, and only pushed out immediately if it owns the message loop (see
+    /// `own_message_loop`). Nothing is sent while paused.
+    ///
+    /// Nothing else a tick does happens, so acks and new peers are only picked up by the next one.
+    pub fn flush(&mut self) -> usize {
+        let sent = self.send_queued();
+        if self.owned_loop.is_some() {
+            self.drive();
+        }
+        sent
+    }
+
+    /// Queues `data` to be sent to every connected peer whose user has `tag`. See `Message::to_tag`.
+    pub fn broadcast_to_tag(&mut self, tag: impl Into<String>, data: M) -> Result<MessageHandle, MessageError> {
+        self.app.message_queue().enqueue(Message::new(data).to_tag(tag))
+    }
+
+    /// Queues a broadcast where every peer gets their own version of the message, e.g. only the entities near
+    /// them. On the next tick `payload` is called for each connected peer that has a user, and whatever it
+    /// returns is sent to just that peer. Peers it returns `None` for are skipped.
+    ///
+    /// All versions share one message id. They're sent after the message queue, and can't be acked.
+    pub fn broadcast_with(&mut self, payload: impl FnMut(&PeerId, &U) -> Option<M> + 'static) {
+        self.tailored_broadcasts.push(Box::new(payload));
+    }
+
+    /// Sends everything `reader` produces to `peer` over many ticks, for transfers too large for one message,
+    /// e.g. a map file. Each tick reads one chunk (see `with_stream_chunk_size`) per stream and sends it on a
+    /// reliable, ordered channel. A chunk the channel has no room for is retried on the next tick, so a big
+    /// transfer doesn't crowd out everything else.
+    ///
+    /// The peer gets the chunks through `TApp::on_stream_chunk`, then `on_stream_complete` once the reader
+    /// runs out, or `on_stream_aborted` if reading failed. Nothing is sent while paused, and the stream is
+    /// dropped if the peer disconnects.
+    pub fn send_stream(&mut self, peer: PeerId, reader: impl Read + 'static) -> StreamId {
+        let id = self.next_stream_id;
+        self.next_stream_id += 1;
+        self.outgoing_streams.push(OutgoingStream::new(id, peer, Box::new(reader)));
+        id
+    }
+
+    /// Whether the stream `id` started with `send_stream` is still being sent.
+    pub fn is_streaming(&self, id: StreamId) -> bool {
+        self.outgoing_streams.iter().any(|stream| stream.id == id)
+    }
+
+    /// Stops handing received messages to the app and sending queued ones, e.g. during a loading screen,
+    /// without dropping the connection. `tick` keeps reading the socket so it doesn't back up, holding on to
+    /// what arrives (up to the capacity set with `with_pause_capacity`) until `resume`.
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    /// Undoes `pause`. Everything held and queued in the meantime is processed on the next tick.
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Drops all pending work involving `peer` without disconnecting them, e.g. when muting them: messages
+    /// queued or held for them, streams to them, packets from them held while paused, and waiting for their
+    /// acks. Broadcasts stop waiting on them too, completing with the acks they got if nobody else is left,
+    /// and messages only they were left to ack are dropped through `TApp::on_ack_abandoned`. Returns how many
+    /// outgoing messages were dropped, their error handlers aren't called.
+    pub fn clear_peer(&mut self, peer: PeerId) -> usize {
+        let queued = self.app.message_queue()
+            .remove_if(|message| matches!(message.target, Target::Peer(to) if to == peer));
+        let held = self.pending_sends.len();
+        self.pending_sends.retain(|(to, _, _)| *to != peer);
+        let dropped = queued + held - self.pending_sends.len();
+
+        self.outgoing_streams.retain(|stream| stream.peer != peer);
+        for fanout in self.fanouts.iter_mut() {
+            fanout.remaining.retain(|to| *to != peer);
+        }
+        self.held_incoming.retain(|(_, from, _)| *from != peer);
+        self.stop_waiting_on(peer);
+        self.complete_finished_acks();
+        net_log!(self, Level::Info, "Cleared pending work for {peer}, dropping {dropped} message(s)");
+        dropped
+    }
+
+    /// Carries a peer that reconnected under a new id over to it: their user moves from `old` to `new` (see
+    /// `UserList::migrate`) and messages still queued or held for `old` go to `new` instead. The old
+    /// connection is ignored from then on, without `post_user_disconnected` firing for it.
+    ///
+    /// The old connection may already be gone: the user of a peer that left within the rejoin window set with
+    /// `with_rejoin_window` is kept for this, unless they were kicked, so they can be moved over even when
+    /// matchbox reported the old connection closed before the new one opened. `post_user_disconnected` has
+    /// fired for them already then. Under `with_manual_users` only users still in the `UserList` can be moved.
+    ///
+    /// Returns whether `old` had a user to move, nothing is changed otherwise.
+    pub fn migrate_user(&mut self, old: PeerId, new: PeerId) -> bool {
+        if self.app.get_users_mut().get(&old).is_none() {
+            if let Some((_, user)) = self.departed_users.remove(&old) {
+                self.app.get_users_mut().insert(old, user);
+            }
+        }
+        if !self.app.get_users_mut().migrate(&old, new) {
+            return false;
+        }
+        net_log!(self, Level::Info, "Migrating peer {old} to {new}");
+
+        self.app.message_queue().retarget(old, new);
+        for (peer, _, message) in self.pending_sends.iter_mut() {
+            if *peer == old {
+                *peer = new;
+                message.target = Target::Peer(new);
+            }
+        }
+
+        // Same sender, so their message ids carry on where they left off
+        if let Some(high_water) = self.dedup.high_water(&old) {
+            self.dedup.raise_floor(new, high_water);
+        }
+
+        // Stop waiting on the old connection, like for a kicked peer but their user lives on
+        if self.connected_peers.contains(&old) {
+            self.ejected.insert(old);
+            self.forget_connection(old);
+            self.complete_finished_acks();
+        }
+        true
+    }
+
+    /// Ejects a peer from our side: their user is removed, messages waiting on their ack are dropped and
+    /// `post_user_disconnected` fires. They're told, so their side drops us too, then anything they send
+    /// is ignored and nothing more is sent to them.
+    ///
+    /// matchbox can't sever the connection to a single peer, so the connection itself stays open until
+    /// they leave or the socket is closed.
+    pub fn kick(&mut self, peer: PeerId) {
+        net_log!(self, Level::Info, "Kicking peer: {peer}");
+        self.send_goodbye(peer);
+        self.eject(peer, DisconnectReason::Kicked);
+    }
+
+    /// Brings the acks pending in the stats up to date, and drops the stats of peers that left.
+    fn update_peer_stats(&mut self) {
+        self.stats.acks_pending = self.messages_waiting_for_ack.len();
+        self.stats.peers.retain(|peer, _| self.connected_peers.contains(peer));
+        for peer in &self.connected_peers {
+            self.stats.peers.entry(*peer).or_default().acks_pending = 0;
+        }
+        for unacked in self.messages_waiting_for_ack.values() {
+            for peer in unacked.missing() {
+                if let Some(stats) = self.stats.peers.get_mut(&peer) {
+                    stats.acks_pending += 1;
+                }
+            }
+        }
+    }
+
+    /// Introduces us to `peer` with the metadata set with `with_hello`.
+    fn send_hello(&mut self, peer: PeerId, meta: Vec<u8>) {
+        let packet = match bincode::serialize(&WirePacket::<M>::Hello(meta)) {
+            Ok(packet) => packet.into_boxed_slice(),
+            Err(e) => {
+                net_log!(self, Level::Warn, "Failed to serialize packet: {e}");
+                return;
+            }
+        };
+        let channel = ChannelInfo::find_reliable(&self.channels, true).unwrap_or(CHANNEL_ID);
+        if let Err(e) = self.send_packet(channel, peer, packet) {
+            net_log!(self, Level::Warn, "Couldn't send our hello to {peer}: {e}");
+        }
+    }
+
+    /// Creates the user of a peer that just connected, with the metadata of their hello if they sent one.
+    fn admit(&mut self, peer: PeerId, meta: Option<&[u8]>) {
+        let user = match meta {
+            Some(meta) => self.app.create_user_with_meta(peer, meta),
+            None => self.app.create_user(peer),
+        };
+        self.app.get_users_mut().insert(peer, user);
+        self.restore_reconnect_mark(peer);
+        self.app.post_user_connected(peer);
+        self.report_rejoin(peer);
+        self.send_on_join(peer);
+        net_log!(self, Level::Info, "Peer connected: {peer}");
+    }
+
+    /// Gives up on the hellos of peers that connected longer than the connect timeout ago, creating their
+    /// users without metadata.
+    fn admit_without_hello(&mut self) {
+        let overdue: Vec<_> = self.awaiting_hello.iter()
+            .filter(|(_, connected_at)| self.elapsed.saturating_sub(**connected_at) > self.connect_timeout)
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in overdue {
+            net_log!(self, Level::Warn, "No hello from {peer}, creating their user without it");
+            self.awaiting_hello.remove(&peer);
+            self.admit(peer, None);
+        }
+    }
+
+    /// Sends every connected peer a heartbeat, if it's time to.
+    fn send_heartbeats(&mut self) {
+        let Some(interval) = self.heartbeat_interval else {
+            return;
+        };
+        if self.elapsed.saturating_sub(self.last_heartbeat) < interval {
+            return;
+        }
+        self.last_heartbeat = self.elapsed;
+
+        let packet: Packet = match bincode::serialize(&WirePacket::<M>::Heartbeat) {
+            Ok(packet) => packet.into_boxed_slice(),
+            Err(e) => {
+                net_log!(self, Level::Warn, "Failed to serialize packet: {e}");
+                return;
+            }
+        };
+        let channel = ChannelInfo::find_unreliable(&self.channels).unwrap_or(CHANNEL_ID);
+        for peer in self.connected_peers.clone() {
+            if let Err(e) = self.send_packet(channel, peer, packet.clone()) {
+                net_log!(self, Level::Debug, "Couldn't send heartbeat to {peer}: {e}");
+            }
+        }
+    }
+
+    /// Tells `peer` we're closing our connection to them, so they drop us right away.
+    fn send_goodbye(&mut self, peer: PeerId) {
+        if !self.connected_peers.contains(&peer) {
+            return;
+        }
+        let packet = match bincode::serialize(&WirePacket::<M>::Goodbye) {
+            Ok(packet) => packet.into_boxed_slice(),
+            Err(e) => {
+                net_log!(self, Level::Warn, "Failed to serialize packet: {e}");
+                return;
+            }
+        };
+        let channel = ChannelInfo::find_reliable(&self.channels, true).unwrap_or(CHANNEL_ID);
+        if let Err(e) = self.send_packet(channel, peer, packet) {
+            net_log!(self, Level::Debug, "Couldn't say goodbye to {peer}: {e}");
+        }
+    }
+
+    /// Removes users we haven't heard anything from within the timeout set with `with_user_timeout`, as of
+    /// `now` (the total time passed into `tick`). They are treated as kicked, and `post_user_disconnected`
+    /// fires with `DisconnectReason::TimedOut`.
+    ///
+    /// Called by `tick`, but can be called directly to test dead-peer handling with a made up clock.
+    pub fn sweep_timeouts(&mut self, now: Duration) {
+        let Some(timeout) = self.user_timeout else {
+            return;
+        };
+
+        let timed_out: Vec<_> = self.last_seen.iter()
+            .filter(|(_, last_seen)| now.saturating_sub(**last_seen) > timeout)
+            .map(|(peer, _)| *peer)
+            .collect();
+
+        for peer in timed_out {
+            net_log!(self, Level::Info, "Peer timed out: {peer}");
+            self.app.on_peer_timeout(peer);
+            self.eject(peer, DisconnectReason::TimedOut);
+        }
+    }
+
+    /// Gives up on messages whose `Message::ack_deadline` passed, calling their timeout handlers.
+    fn expire_ack_deadlines(&mut self) {
+        let expired: Vec<_> = self.messages_waiting_for_ack.iter()
+            .filter(|(_, unacked)| unacked.is_past_deadline(self.elapsed))
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in expired {
+            self.give_up_on(id, false);
+        }
+    }
+
+    /// Sends messages whose `Message::with_ack_timeout` passed again to the peers that haven't acked, or gives
+    /// up on them once out of retries.
+    fn retry_unacked(&mut self) {
+        let timed_out: Vec<_> = self.messages_waiting_for_ack.iter()
+            .filter(|(_, unacked)| unacked.is_past_ack_timeout(self.elapsed))
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in timed_out {
+            let Some(unacked) = self.messages_waiting_for_ack.get(&id) else {
+                continue;
+            };
+            if unacked.retries >= unacked.message.ack_retries.unwrap_or(DEFAULT_ACK_RETRIES) {
+                self.give_up_on(id, true);
+                continue;
+            }
+
+            let missing = unacked.missing();
+            let fanout_rate = unacked.message.fanout_rate;
+            let channel = self.channel_for(&unacked.message);
+            let packet = match Self::pack(&unacked.message) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    net_log!(self, Level::Warn, "Failed to serialize message {id} to send it again: {e}");
+                    continue;
+                }
+            };
+            net_log!(self, Level::Debug, "Sending message {id} again to {} peer(s) that haven't acked", missing.len());
+            if let Some(rate) = fanout_rate {
+                self.extend_fanout(id, channel, packet, missing, rate);
+            }
+            else {
+                for peer in missing {
+                    self.app.on_wire_out(id, peer, &packet);
+                    match self.send_packet(channel, peer, packet.clone()) {
+                        Ok(()) => self.stats.record_sent(peer),
+                        Err(e) => {
+                            net_log!(self, Level::Warn, "Failed to send message {id} again to {peer}: {e}");
+                            self.record_dropped(e);
+                        }
+                    }
+                }
+            }
+            if let Some(unacked) = self.messages_waiting_for_ack.get_mut(&id) {
+                unacked.retries += 1;
+                unacked.last_sent_at = self.elapsed;
+            }
+        }
+    }
+
+    /// Stops waiting on acks for message `id`, calling its timeout handler with the peers that didn't ack, and
+    /// `TApp::on_ack_timeout` for each of them when it ran `out_of_retries`.
+    fn give_up_on(&mut self, id: MessageId, out_of_retries: bool) {
+        let Some(mut unacked) = self.messages_waiting_for_ack.remove(&id) else {
+            return;
+        };
+        let missing = unacked.missing();
+        net_log!(self, Level::Debug, "Message {id} wasn't acked in time by {} peer(s), giving up", missing.len());
+        self.stats.ack_timeouts += 1;
+        if out_of_retries {
+            for peer in &missing {
+                self.app.on_ack_timeout(id, *peer);
+            }
+        }
+        if let Some(handler) = unacked.message.timeout_handler.as_mut() {
+            handler(&mut self.app, id, &missing);
+        }
+    }
+
+    /// Notes when `peer` left, for `report_rejoin`, and forgets peers that left longer than the rejoin window
+    /// ago.
+    fn remember_departure(&mut self, peer: PeerId) {
+        let (now, window) = (self.elapsed, self.rejoin_window);
+        self.departed.retain(|_, left_at| now.saturating_sub(*left_at) <= window);
+        self.departed_keys.retain(|_, left_at| now.saturating_sub(*left_at) <= window);
+        self.departed_users.retain(|_, (left_at, _)| now.saturating_sub(*left_at) <= window);
+        self.departed.insert(peer, now);
+        if let Some(key) = self.reconnect_key_of(peer) {
+            self.departed_keys.insert(key, now);
+        }
+    }
+
+    /// Tells the app when a newly connected `peer` had left within the rejoin window, recognising them by their
+    /// reconnect key if there is one, or else by their peer id.
+    fn report_rejoin(&mut self, peer: PeerId) {
+        let by_key = self.reconnect_key_of(peer).and_then(|key| self.departed_keys.remove(&key));
+        let Some(left_at) = by_key.or_else(|| self.departed.remove(&peer)) else {
+            return;
+        };
+        let was_gone_for = self.elapsed.saturating_sub(left_at);
+        if was_gone_for <= self.rejoin_window {
+            net_log!(self, Level::Info, "Peer {peer} rejoined after {was_gone_for:?}");
+            self.app.on_peer_rejoined(peer, was_gone_for);
+        }
+    }
+
+    /// The key set with `with_reconnect_dedup` of the user of `peer`, if they have a user and a key.
+    fn reconnect_key_of(&mut self, peer: PeerId) -> Option<u64> {
+        let reconnect_key = self.reconnect_key.as_ref()?;
+        self.app.get_users_mut().get(&peer).and_then(|user| reconnect_key(&peer, user))
+    }
+
+    /// Keeps the highest message id received from `peer` under their reconnect key, before their dedup
+    /// window is forgotten.
+    fn remember_reconnect_mark(&mut self, peer: PeerId) {
+        let Some(key) = self.reconnect_key_of(peer) else {
+            return;
+        };
+        if let Some(high_water) = self.dedup.high_water(&peer) {
+            let mark = self.reconnect_marks.entry(key).or_insert(high_water);
+            *mark = (*mark).max(high_water);
+        }
+    }
+
+    /// Carries the mark kept for the reconnect key of a newly connected `peer` over to their dedup window.
+    fn restore_reconnect_mark(&mut self, peer: PeerId) {
+        let Some(key) = self.reconnect_key_of(peer) else {
+            return;
+        };
+        if let Some(high_water) = self.reconnect_marks.remove(&key) {
+            net_log!(self, Level::Debug, "Peer {peer} reconnected, treating message ids up to {high_water} as seen");
+            self.dedup.raise_floor(peer, high_water);
+        }
+    }
+
+    /// Drops everything kept about the connection to `peer`, and stops waiting on their acks.
+    fn forget_connection(&mut self, peer: PeerId) {
+        self.last_seen.remove(&peer);
+        self.rtt.remove(&peer);
+        self.dedup.forget_peer(&peer);
+        self.reassembly.forget_peer(&peer);
+        self.forget_sequences(peer);
+        self.awaiting_hello.remove(&peer);
+        self.outgoing_streams.retain(|stream| stream.peer != peer);
+        self.connected_peers.retain(|connected| *connected != peer);
+        self.stop_waiting_on(peer);
+    }
+
+    /// Drops the packet numbering of sequenced channels to and from `peer`.
+    fn forget_sequences(&mut self, peer: PeerId) {
+        self.next_seq.retain(|(to_peer, _), _| *to_peer != peer);
+        self.sequencer.forget_peer(&peer);
+    }
+
+    /// Stops waiting for `peer` to ack messages sent to them, dropping those only they were left to ack.
+    /// Broadcasts nobody is left to ack are kept, to complete with the acks they got.
+    fn stop_waiting_on(&mut self, peer: PeerId) {
+        let mut changed = Vec::new();
+        let mut abandoned = Vec::new();
+        self.messages_waiting_for_ack.retain(|id, unacked| {
+            if !unacked.recipients.contains(&peer) {
+                return true;
+            }
+            unacked.recipients.retain(|recipient| *recipient != peer);
+            changed.push(*id);
+            let keep = unacked.was_broadcast() || unacked.fanning_out || !unacked.recipients.is_empty();
+            if !keep {
+                abandoned.push(*id);
+            }
+            keep
+        });
+
+        for id in changed {
+            self.app.on_ack_target_changed(id, &[], &[peer]);
+        }
+        for id in abandoned {
+            net_log!(self, Level::Debug, "Giving up on message {id}, {peer} left before acking it");
+            self.app.on_ack_abandoned(id, peer);
+        }
+    }
+
+    /// Brings the peers messages wait on up to date with who connected and disconnected since the last tick.
+    fn update_ack_targets(&mut self, joined: &[PeerId], left: &[PeerId]) {
+        for peer in left {
+            self.stop_waiting_on(*peer);
+        }
+
+        if self.late_join_policy == LateJoinPolicy::Include && !joined.is_empty() {
+            let broadcasts: Vec<_> = self.messages_waiting_for_ack.iter()
+                .filter(|(_, unacked)| unacked.was_broadcast())
+                .map(|(id, _)| *id)
+                .collect();
+            for id in broadcasts {
+                self.extend_broadcast(id, joined);
+            }
+        }
+
+        self.complete_finished_acks();
+    }
+
+    /// Sends broadcast `id`, still waiting on acks, to the peers in `joined` it wasn't sent to, and waits on
+    /// them too.
+    fn extend_broadcast(&mut self, id: MessageId, joined: &[PeerId]) {
+        let Some(unacked) = self.messages_waiting_for_ack.get(&id) else {
+            return;
+        };
+        let added: Vec<_> = joined.iter().filter(|peer| !unacked.recipients.contains(peer)).copied().collect();
+        if added.is_empty() {
+            return;
+        }
+        let channel = self.channel_for(&unacked.message);
+        let packet = match Self::pack(&unacked.message) {
+            Ok(packet) => packet,
+            Err(e) => {
+                net_log!(self, Level::Warn, "Failed to serialize message {id} for peers that joined: {e}");
+                return;
+            }
+        };
+
+        for peer in &added {
+            self.app.on_wire_out(id, *peer, &packet);
+            match self.send_packet(channel, *peer, packet.clone()) {
+                Ok(()) => self.stats.record_sent(*peer),
+                Err(e) => {
+                    net_log!(self, Level::Warn, "Failed to send message {id} to {peer}, who joined after it was sent: {e}");
+                    self.record_dropped(e);
+                }
+            }
+        }
+        if let Some(unacked) = self.messages_waiting_for_ack.get_mut(&id) {
+            unacked.recipients.extend(&added);
+        }
+        self.app.on_ack_target_changed(id, &added, &[]);
+    }
+
+    /// Serializes a message for the wire.
+    fn pack(message: &Message<U, T, M>) -> Result<Packet, bincode::Error> {
+        let packet = bincode::serialize(&WirePacket::Message(PackedMessage {
+            id: message.id,
+            data: message.data.clone(),
+            must_ack: message.wants_ack(),
+            trace_id: message.trace_id,
+            origin: message.origin,
+            exactly_once: message.delivery == Delivery::ExactlyOnce,
+        }))?;
+        Ok(packet.into_boxed_slice())
+    }
+
+    fn eject(&mut self, peer: PeerId, reason: DisconnectReason) {
+        if !self.ejected.insert(peer) {
+            return;
+        }
+
+        self.remember_departure(peer);
+        self.remember_reconnect_mark(peer);
+        self.forget_connection(peer);
+        if let Some(user) = self.app.get_users_mut().remove(&peer) {
+            if reason != DisconnectReason::Kicked {
+                self.departed_users.insert(peer, (self.elapsed, user));
+            }
+            self.app.post_user_disconnected(peer, reason);
+        }
+
+        // Broadcasts may have only been waiting on them
+        self.complete_finished_acks();
+    }
+
+    /// Processes everything that happened since the last tick and sends what was queued.
+    ///
+    /// Sends are handed to matchbox here, but only reach the wire once its message loop future runs next.
+    /// Give the loop to `own_message_loop` to have it run as part of every tick.
+    ///
+    /// A packet that can't be read or sent doesn't stop the tick, it's collected in the returned report's
+    /// errors, along with those of `flush` calls since the last tick. Returns `NetworkError::SocketFailed`
+    /// instead once the socket's message loop ended with an error, after which nothing goes in or out anymore.
+    pub fn tick(&mut self, delta: Duration) -> Result<TickReport, NetworkError> {
+        let (sent_before, received_before) = (self.stats.messages_sent, self.stats.messages_received);
+        self.elapsed += delta;
+        self.app.message_queue().set_clock(self.elapsed);
+        if self.owned_loop.is_some() {
+            self.drive();
+        }
+        if self.local_peer_id.is_none() {
+            self.local_peer_id = self.socket.id();
+            if self.local_peer_id.is_some() {
+                net_log!(self, Level::Info, "Connected to the signaling server");
+            }
+        }
+
+        for (peer_id, state) in self.socket.update_peers() {
+            if self.ejected.contains(&peer_id) {
+                // We already said goodbye to them when they were ejected
+                if state == PeerState::Disconnected {
+                    self.ejected.remove(&peer_id);
+                }
+                continue;
+            }
+
+            self.app.on_peer_state_changed(peer_id, state);
+            if self.manual_users {
+                net_log!(self, Level::Info, "Peer {peer_id} is now {state:?}");
+                match state {
+                    PeerState::Connected => {
+                        self.last_seen.insert(peer_id, self.elapsed);
+                        self.report_rejoin(peer_id);
+                    }
+                    PeerState::Disconnected => {
+                        self.remember_departure(peer_id);
+                        self.last_seen.remove(&peer_id);
+                        self.rtt.remove(&peer_id);
+                        self.dedup.forget_peer(&peer_id);
+                        self.reassembly.forget_peer(&peer_id);
+                        self.forget_sequences(peer_id);
+                        self.outgoing_streams.retain(|stream| stream.peer != peer_id);
+                    }
+                }
+                continue;
+            }
+
+            match state {
+                PeerState::Connected => {
+                    self.app.on_peer_connecting(peer_id);
+                    if !self.app.should_accept(peer_id) {
+                        // Ignored like a kicked peer, until matchbox reports them gone
+                        net_log!(self, Level::Info, "Rejected peer: {peer_id}");
+                        self.ejected.insert(peer_id);
+                        continue;
+                    }
+
+                    self.last_seen.insert(peer_id, self.elapsed);
+                    if let Some(meta) = self.hello.clone() {
+                        self.send_hello(peer_id, meta);
+                        self.awaiting_hello.insert(peer_id, self.elapsed);
+                        continue;
+                    }
+                    self.admit(peer_id, None);
+                }
+                PeerState::Disconnected => {
+                    net_log!(self, Level::Info, "Peer disconnected: {peer_id}");
+                    self.remember_departure(peer_id);
+                    self.remember_reconnect_mark(peer_id);
+                    self.last_seen.remove(&peer_id);
+                    self.rtt.remove(&peer_id);
+                    self.dedup.forget_peer(&peer_id);
+                    self.reassembly.forget_peer(&peer_id);
+                    self.forget_sequences(peer_id);
+                    self.outgoing_streams.retain(|stream| stream.peer != peer_id);
+                    if self.awaiting_hello.remove(&peer_id).is_some() {
+                        // They never got a user
+                        continue;
+                    }
+                    match self.app.get_users_mut().remove(&peer_id){
+                        Some(user) => {
+                            self.departed_users.insert(peer_id, (self.elapsed, user));
+                            self.app.post_user_disconnected(peer_id, DisconnectReason::Disconnected);
+                        }
+                        None => net_log!(self, Level::Warn, "Peer disconnected but no user found"),
+                    }
+                }
+            }
+        }
+
+        self.admit_without_hello();
+
+        let previous = std::mem::replace(
+            &mut self.connected_peers,
+            self.socket.connected_peers().filter(|peer| !self.ejected.contains(peer)).collect(),
+        );
+        let joined: Vec<_> = self.connected_peers.iter().filter(|peer| !previous.contains(peer)).copied().collect();
+        let left: Vec<_> = previous.iter().filter(|peer| !self.connected_peers.contains(peer)).copied().collect();
+        if !joined.is_empty() || !left.is_empty() {
+            self.update_ack_targets(&joined, &left);
+        }
+        if self.connected_peers.len() != self.reported_peer_count {
+            let old = std::mem::replace(&mut self.reported_peer_count, self.connected_peers.len());
+            self.app.on_peer_count_changed(old, self.reported_peer_count);
+        }
+
+        // Accept any messages incoming, holding on to the acks we owe so each peer gets them in one packet per
+        // channel
+        let mut received = Vec::new();
+        for channel in self.channel_indices() {
+            for (from_peer, packet) in self.receive_packets(channel) {
+                received.push((channel, from_peer, packet));
+            }
+        }
+
+        let incoming_packets = match self.paused {
+            true => {
+                self.hold_incoming(received);
+                Vec::new()
+            }
+            false => {
+                let mut incoming_packets = std::mem::take(&mut self.held_incoming);
+                incoming_packets.extend(received);
+                incoming_packets
+            }
+        };
+
+        // Packets released from a sequenced channel were already seen on the wire, wrapped
+        let mut incoming_packets: VecDeque<_> = incoming_packets.into_iter()
+            .map(|(channel, from_peer, packet)| (channel, from_peer, packet, false))
+            .collect();
+        if !self.paused {
+            let overdue = self.sequencer.release_overdue(self.elapsed, self.sequence_timeout);
+            for (from_peer, channel, packet) in overdue.into_iter().rev() {
+                net_log!(self, Level::Debug, "Gave up waiting on a sequenced packet from {from_peer} on channel {channel}");
+                incoming_packets.push_front((channel, from_peer, packet.into_boxed_slice(), true));
+            }
+        }
+
+        // Acks go back on the channel the message came in on, so they get the same delivery guarantees
+        let acks_to_send = self.handle_incoming(incoming_packets);
+
+        // Send the acks we owe, one batch per peer and channel
+        for ((to_peer, channel), acks) in acks_to_send {
+            let ack_count = acks.len() as u64;
+            let packet = match bincode::serialize(&WirePacket::AckBatch(acks)) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    net_log!(self, Level::Warn, "Failed to serialize packet: {e}");
+                    self.stats.dropped.serialize_error += ack_count;
+                    self.tick_errors.push(NetworkError::Send(SendError::Serialize(e.to_string())));
+                    continue;
+                }
+            }.into_boxed_slice();
+
+            if let Err(e) = self.send_packet(channel, to_peer, packet) {
+                net_log!(self, Level::Warn, "Failed to send acks: {e}");
+                for _ in 0..ack_count {
+                    self.stats.dropped.record(&e);
+                }
+                self.tick_errors.push(NetworkError::Send(e));
+            }
+        }
+
+        self.sweep_timeouts(self.elapsed);
+        self.reassembly.expire(self.elapsed, self.reassembly_timeout);
+        self.expire_ack_deadlines();
+        self.retry_unacked();
+
+        // Send any messages waiting to be sent, unless paused in which case they wait in the queue. Messages
+        // spread over several ticks go first, so those sent this tick don't get a second round right away.
+        if !self.paused {
+            self.continue_fanouts();
+        }
+        self.send_queued();
+
+        if !self.paused {
+            for payload in std::mem::take(&mut self.tailored_broadcasts) {
+                self.send_tailored(payload);
+            }
+            self.send_stream_chunks();
+            self.send_heartbeats();
+        }
+
+        // Let out any packets the simulated network conditions were holding back
+        if let Some(link) = self.conditions.as_mut() {
+            for delayed in link.take_due_outgoing(self.elapsed) {
+                if let Err(e) = Self::try_send(&mut self.socket, delayed.channel, delayed.peer, delayed.packet) {
+                    net_log!(self, Level::Warn, "Failed to send delayed packet: {e}");
+                    self.record_dropped(e);
+                }
+            }
+        }
+
+        // Push what we just sent out right away
+        if self.owned_loop.is_some() {
+            self.drive();
+        }
+
+        self.update_peer_stats();
+
+        #[cfg(feature = "metrics")]
+        crate::telemetry::publish(&self.stats, self.messages_waiting_for_ack.len(), self.connected_peers.len());
+
+        if let Some(receipts) = self.receipts.as_mut().filter(|receipts| !receipts.is_empty()) {
+            let receipts = std::mem::take(receipts);
+            self.app.on_receipts(&receipts);
+        }
+
+        match self.fixed_step {
+            Some(step) => {
+                self.step_accumulator += delta;
+                while self.step_accumulator >= step {
+                    self.step_accumulator -= step;
+                    self.app.tick(step);
+                }
+            }
+            None => self.app.tick(delta),
+        }
+        self.app.post_tick(delta);
+
+        if self.connection_status() == ConnectionStatus::Failed {
+            self.tick_errors.clear();
+            return Err(NetworkError::SocketFailed);
+        }
+        Ok(TickReport {
+            messages_sent: self.stats.messages_sent - sent_before,
+            messages_received: self.stats.messages_received - received_before,
+            errors: std::mem::take(&mut self.tick_errors),
+        })
+    }
+
+    /// Sends everything in the message queue and the messages held for peers that are ready now, returning how
+    /// many messages went out.
+    fn send_queued(&mut self) -> usize {
+        let outgoing = match self.paused {
+            true => Vec::new(),
+            false => {
+                let mut outgoing = self.take_ready_pending_sends();
+                let queued = self.app.message_queue().drain(..);
+                for message in queued.iter() {
+                    let dwell = self.elapsed.saturating_sub(message.queued_at);
+                    self.stats.avg_queue_latency = self.stats.avg_queue_latency.mul_f64(1.0 - QUEUE_LATENCY_SMOOTHING)
+                        + dwell.mul_f64(QUEUE_LATENCY_SMOOTHING);
+                }
+                outgoing.extend(queued);
+                self.coalesce(outgoing)
+            }
+        };
+        let mut sent = 0;
+        for mut message in outgoing {
+            if message.handle.as_ref().is_some_and(|handle| handle.is_cancelled()) {
+                net_log!(self, Level::Debug, "Skipping a cancelled message");
+                continue;
+            }
+
+            if let Target::Peer(to_peer) = message.target {
+                if !self.channel_ready(self.channel_for(&message), to_peer) {
+                    match self.has_left(to_peer) {
+                        true => {
+                            net_log!(self, Level::Warn, "Dropping message to {to_peer}, they aren't connected");
+                            self.fail_send(&mut message, SendError::NoSuchPeer { peer: to_peer });
+                        }
+                        // They may just not be fully connected yet
+                        false => self.pending_sends.push((to_peer, self.elapsed, message)),
+                    }
+                    continue;
+                }
+            }
+
+            message.id = self.next_message_id;
+
+            let packet = match Self::pack(&message) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    net_log!(self, Level::Warn, "Failed to serialize packet: {e}");
+                    self.fail_send(&mut message, SendError::Serialize(e.to_string()));
+                    continue;
+                }
+            };
+
+            let recipients = self.resolve_recipients(&message.target);
+            let channel = self.channel_for(&message);
+            let mut later: VecDeque<PeerId> = recipients.iter().copied().collect();
+            let now: Vec<PeerId> = match message.fanout_rate {
+                Some(rate) => later.drain(..rate.min(later.len())).collect(),
+                None => later.drain(..).collect(),
+            };
+            // Only peers it went out to are waited on
+            let mut sent_to = Vec::new();
+            for peer in now {
+                self.app.on_wire_out(message.id, peer, &packet);
+                match self.send_packet(channel, peer, packet.clone()) {
+                    Ok(()) => {
+                        self.stats.record_sent(peer);
+                        sent_to.push(peer);
+                    }
+                    Err(e) => {
+                        net_log!(self, Level::Warn, "Failed to send message {}: {e}", message.id);
+                        self.fail_send(&mut message, e);
+                    }
+                }
+            }
+            let fanning_out = !later.is_empty();
+            if let (true, Some(rate)) = (fanning_out, message.fanout_rate) {
+                self.fanouts.push(Fanout {
+                    id: message.id,
+                    channel,
+                    packet: packet.clone(),
+                    remaining: later,
+                    rate,
+                    send_error_handler: message.send_error_handler.take(),
                 });
             }
+
+            if message.loopback {
+                match self.socket.id() {
+                    Some(local_peer) => {
+                        let ctx = ReceiveContext {
+                            id: message.id,
+                            from_peer: local_peer,
+                            channel,
+                            trace_id: message.trace_id,
+                            origin: message.origin,
+                        };
+                        self.dispatch_receive(ctx, false, &message.data);
+                        self.feed_streams(ctx, false, &message.data);
+                    }
+                    None => net_log!(self, Level::Warn, "Skipping loopback of message {}, we have no peer id yet", message.id),
+                }
+            }
+
+            let id = self.next_message_id;
+            self.next_message_id += 1;
+            sent += 1;
+            if let Some(handle) = &message.handle {
+                handle.assign(id);
+            }
+
+            // A message that reached nobody it was meant for has nothing to wait on, its errors were reported
+            let reached_nobody = sent_to.is_empty() && !recipients.is_empty() && !fanning_out;
+            if message.wants_ack() && !reached_nobody {
+                message.ack_timeout = message.ack_timeout.or(self.ack_timeout);
+                message.ack_retries.get_or_insert(self.ack_retries);
+                self.messages_waiting_for_ack.insert(id,MessageWaitingForAck {
+                    message,
+                    recipients: sent_to,
+                    responses: Vec::new(),
+                    sent_at: self.elapsed,
+                    last_sent_at: self.elapsed,
+                    retries: 0,
+                    fanning_out,
+                });
+
+                // Nobody to wait on, e.g. a filter that matched no one
+                if self.messages_waiting_for_ack[&id].is_complete() {
+                    self.complete_ack(id);
+                }
+            }
+        }
+        sent
+    }
+
+    fn send_on_join(&mut self, peer: PeerId) {
+        let Some(sender) = self.join_sender.as_mut() else {
+            return;
+        };
+        let Some(message) = sender(&mut self.app, peer) else {
+            return;
+        };
+        if let Err(e) = self.app.message_queue().enqueue(message.to_peer(peer)) {
+            net_log!(self, Level::Warn, "Couldn't queue the join message for {peer}: {e}");
+        }
+    }
+
+    fn log(&self, level: Level, args: fmt::Arguments) {
+        match (self.log_sink.as_ref(), self.name.as_deref()) {
+            (Some(sink), Some(name)) => sink(level, &format!("[{name}] {args}")),
+            (Some(sink), None) => sink(level, &args.to_string()),
+            (None, Some(name)) => log::log!(level, "[{name}] {args}"),
+            (None, None) => log::log!(level, "{args}"),
+        }
+    }
+
+    /// Drops every message that a later one with the same coalesce key supersedes.
+    fn coalesce(&mut self, messages: Vec<Message<U, T, M>>) -> Vec<Message<U, T, M>> {
+        let before = messages.len();
+        let mut seen = HashSet::new();
+        let mut kept: Vec<_> = messages.into_iter()
+            .rev()
+            .filter(|message| message.coalesce_key.is_none_or(|key| seen.insert(key)))
+            .collect();
+        kept.reverse();
+        self.stats.coalesced += (before - kept.len()) as u64;
+        kept
+    }
+
+    /// Whether `peer` was ejected, or left within the rejoin window and hasn't connected again, so messages
+    /// to them can fail straight away instead of waiting for them to connect.
+    fn has_left(&self, peer: PeerId) -> bool {
+        let departed = self.departed.get(&peer)
+            .is_some_and(|left_at| self.elapsed.saturating_sub(*left_at) <= self.rejoin_window);
+        self.ejected.contains(&peer) || (departed && !self.connected_peers.contains(&peer))
+    }
+
+    /// Takes the held messages whose peer is ready now. Peers that had messages held for longer than the
+    /// connect timeout are given up on, along with everything held for them, and so are peers that left in
+    /// the meantime.
+    fn take_ready_pending_sends(&mut self) -> Vec<Message<U, T, M>> {
+        let left: HashSet<PeerId> = self.pending_sends.iter()
+            .map(|(peer, _, _)| *peer)
+            .filter(|peer| self.has_left(*peer))
+            .collect();
+        let failed: HashSet<PeerId> = self.pending_sends.iter()
+            .filter(|(peer, held_since, message)| {
+                !left.contains(peer)
+                    && !self.channel_ready(self.channel_for(message), *peer)
+                    && self.elapsed.saturating_sub(*held_since) > self.connect_timeout
+            })
+            .map(|(peer, _, _)| *peer)
+            .collect();
+
+        let mut ready = Vec::new();
+        for (peer, held_since, mut message) in std::mem::take(&mut self.pending_sends) {
+            if left.contains(&peer) || failed.contains(&peer) {
+                self.fail_send(&mut message, SendError::NoSuchPeer { peer });
+            }
+            else if self.channel_ready(self.channel_for(&message), peer) {
+                ready.push(message);
+            }
+            else {
+                self.pending_sends.push((peer, held_since, message));
+            }
+        }
+
+        for peer in left {
+            net_log!(self, Level::Warn, "Peer {peer} left, dropping the messages held for them");
+        }
+        for peer in failed {
+            net_log!(self, Level::Warn, "Peer {peer} didn't connect in time, dropping the messages held for them");
+            self.app.on_connect_failed(peer);
+        }
+        ready
+    }
+
+    /// Counts a message that won't go out and lets its error handler know.
+    fn fail_send(&mut self, message: &mut Message<U, T, M>, error: SendError) {
+        if let Some(handler) = message.send_error_handler.as_mut() {
+            handler(&mut self.app, &error);
+        }
+        self.record_dropped(error);
+    }
+
+    /// Counts a packet that won't go out, and reports why with the tick.
+    fn record_dropped(&mut self, error: SendError) {
+        self.stats.dropped.record(&error);
+        self.tick_errors.push(NetworkError::Send(error));
+    }
+
+    /// Works out who a message is sent to right now.
+    fn resolve_recipients(&mut self, target: &Target<U>) -> Vec<PeerId> {
+        match target {
+            Target::Peer(to_peer) => vec![*to_peer],
+            // Broadcast to all connected peers
+            Target::Broadcast => self.connected_peers.clone(),
+            Target::Where(filter) => {
+                let users = self.app.get_users_mut();
+                self.connected_peers.iter()
+                    .filter(|peer| users.get(peer).is_some_and(|user| filter(peer, user)))
+                    .copied()
+                    .collect()
+            }
+            Target::Peers(peers) => {
+                let mut recipients: Vec<PeerId> = Vec::with_capacity(peers.len());
+                for peer in peers {
+                    if !self.connected_peers.contains(peer) {
+                        net_log!(self, Level::Debug, "Skipping {peer} for a message to a list of peers, they aren't connected");
+                    } else if !recipients.contains(peer) {
+                        recipients.push(*peer);
+                    }
+                }
+                recipients
+            }
+            Target::AllExcept(excluded) => self.connected_peers.iter()
+                .filter(|peer| *peer != excluded)
+                .copied()
+                .collect(),
+            Target::Best(count) => {
+                let mut peers = self.connected_peers.clone();
+                peers.sort_by_key(|peer| self.rtt.get(peer).copied().unwrap_or(Duration::MAX));
+                peers.truncate(*count);
+                peers
+            }
+            Target::Random { count, seed, weight } => {
+                // Sorted so the seed alone decides the pick, not the order matchbox lists peers in
+                let mut peers = self.connected_peers.clone();
+                peers.sort();
+                let users = self.app.get_users_mut();
+                let mut rng = SimRng::new(*seed);
+                // Weighted sampling without replacement: each peer draws u^(1/weight), the highest draws win
+                let mut keyed: Vec<_> = peers.into_iter()
+                    .filter_map(|peer| {
+                        let weight = match weight {
+                            Some(weight) => weight(&peer, users.get(&peer)?),
+                            None => 1.0,
+                        };
+                        let draw = rng.next_f64();
+                        (weight > 0.0).then(|| (draw.powf(1.0 / weight), peer))
+                    })
+                    .collect();
+                keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+                keyed.truncate(*count);
+                keyed.into_iter().map(|(_, peer)| peer).collect()
+            }
+        }
+    }
+
+    fn channel_indices(&self) -> Vec<usize> {
+        self.channels.iter().map(|channel| channel.index).collect()
+    }
+
+    /// Picks the channel a message goes out on based on the options it was built with.
+    fn channel_for(&self, message: &Message<U, T, M>) -> usize {
+        match (message.channel, message.ordered) {
+            (Some(channel), _) if self.channels.iter().any(|info| info.index == channel) => channel,
+            (Some(channel), _) => {
+                net_log!(self, Level::Warn, "Message {} was sent on channel {channel}, which doesn't exist", message.id);
+                CHANNEL_ID
+            }
+            (None, _) if message.reliability == Some(Reliability::Unreliable) => {
+                ChannelInfo::find_unreliable(&self.channels).unwrap_or(CHANNEL_ID)
+            }
+            (None, Some(ordered)) => ChannelInfo::find_reliable(&self.channels, ordered).unwrap_or(CHANNEL_ID),
+            (None, None) if message.reliability == Some(Reliability::Reliable) => {
+                ChannelInfo::find_reliable(&self.channels, true).unwrap_or(CHANNEL_ID)
+            }
+            (None, None) => self.route(message.id, &message.data),
+        }
+    }
+
+    /// Picks the channel for `data` with the channel router, if there is one.
+    fn route(&self, id: MessageId, data: &M) -> usize {
+        match self.channel_router.as_ref().and_then(|router| router(data)) {
+            Some(channel) if self.channels.iter().any(|info| info.index == channel) => channel,
+            Some(channel) => {
+                net_log!(self, Level::Warn, "Message {id} was routed to channel {channel}, which doesn't exist");
+                CHANNEL_ID
+            }
+            None => CHANNEL_ID,
+        }
+    }
+
+    /// Sends messages spread out with `Message::with_fanout_rate` to the next few of their recipients, who are
+    /// waited on for acks from then on.
+    fn continue_fanouts(&mut self) {
+        for mut fanout in std::mem::take(&mut self.fanouts) {
+            let mut sent = 0;
+            while sent < fanout.rate {
+                let Some(peer) = fanout.remaining.pop_front() else {
+                    break;
+                };
+                if !self.connected_peers.contains(&peer) {
+                    continue;
+                }
+                sent += 1;
+                self.app.on_wire_out(fanout.id, peer, &fanout.packet);
+                match self.send_packet(fanout.channel, peer, fanout.packet.clone()) {
+                    Ok(()) => {
+                        self.stats.record_sent(peer);
+                        let unacked = self.messages_waiting_for_ack.get_mut(&fanout.id);
+                        if let Some(unacked) = unacked.filter(|unacked| !unacked.recipients.contains(&peer)) {
+                            unacked.recipients.push(peer);
+                        }
+                    }
+                    Err(e) => {
+                        net_log!(self, Level::Warn, "Failed to send message {}: {e}", fanout.id);
+                        if let Some(handler) = fanout.send_error_handler.as_mut() {
+                            handler(&mut self.app, &e);
+                        }
+                        self.record_dropped(e);
+                    }
+                }
+            }
+            if !fanout.remaining.is_empty() {
+                self.fanouts.push(fanout);
+                continue;
+            }
+
+            let Some(unacked) = self.messages_waiting_for_ack.get_mut(&fanout.id) else {
+                continue;
+            };
+            unacked.fanning_out = false;
+            if unacked.recipients.is_empty() && unacked.responses.is_empty() {
+                net_log!(self, Level::Debug, "Message {} reached nobody, not waiting on acks for it", fanout.id);
+                self.messages_waiting_for_ack.remove(&fanout.id);
+            }
+            else if unacked.is_complete() {
+                self.complete_ack(fanout.id);
+            }
+        }
+    }
+
+    /// Has the fanout of message `id` send it to `peers` too, starting one if it's done already.
+    fn extend_fanout(&mut self, id: MessageId, channel: usize, packet: Packet, peers: Vec<PeerId>, rate: usize) {
+        match self.fanouts.iter_mut().find(|fanout| fanout.id == id) {
+            Some(fanout) => {
+                let new: Vec<_> = peers.into_iter().filter(|peer| !fanout.remaining.contains(peer)).collect();
+                fanout.remaining.extend(new);
+            }
+            None => self.fanouts.push(Fanout {
+                id,
+                channel,
+                packet,
+                remaining: peers.into(),
+                rate,
+                send_error_handler: None,
+            }),
+        }
+    }
+
+    /// Sends the next chunk of every stream whose peer is ready for it, ending the streams that ran out.
+    fn send_stream_chunks(&mut self) {
+        let channel = ChannelInfo::find_reliable(&self.channels, true).unwrap_or(CHANNEL_ID);
+        for mut stream in std::mem::take(&mut self.outgoing_streams) {
+            if !self.channel_ready(channel, stream.peer) {
+                // They may just not be fully connected yet, disconnecting drops the stream
+                self.outgoing_streams.push(stream);
+                continue;
+            }
+
+            let (packet, chunk) = match stream.next_chunk(self.stream_chunk_size) {
+                Ok(chunk) if chunk.is_empty() => (WirePacket::<M>::StreamEnd { stream: stream.id, aborted: false }, None),
+                Ok(chunk) => (WirePacket::StreamChunk { stream: stream.id, data: chunk.clone() }, Some(chunk)),
+                Err(e) => {
+                    net_log!(self, Level::Warn, "Failed to read stream {} to {}: {e}", stream.id, stream.peer);
+                    (WirePacket::StreamEnd { stream: stream.id, aborted: true }, None)
+                }
+            };
+            let packet = match bincode::serialize(&packet) {
+                Ok(packet) => packet.into_boxed_slice(),
+                Err(e) => {
+                    net_log!(self, Level::Warn, "Failed to serialize packet: {e}");
+                    self.record_dropped(SendError::Serialize(e.to_string()));
+                    continue;
+                }
+            };
+
+            match (self.send_packet(channel, stream.peer, packet), chunk) {
+                (Ok(()), Some(_)) => self.outgoing_streams.push(stream),
+                (Ok(()), None) => net_log!(self, Level::Debug, "Finished stream {} to {}", stream.id, stream.peer),
+                (Err(SendError::BufferFull { .. }), chunk) => {
+                    // Try again next tick, the end of the stream too
+                    if let Some(chunk) = chunk {
+                        stream.hold(chunk);
+                    }
+                    self.outgoing_streams.push(stream);
+                }
+                (Err(e), _) => {
+                    net_log!(self, Level::Warn, "Dropping stream {} to {}: {e}", stream.id, stream.peer);
+                    self.record_dropped(e);
+                }
+            }
+        }
+    }
+
+    /// Sends each connected peer their own version of a message queued with `broadcast_with`.
+    fn send_tailored(&mut self, mut payload: TailoredPayload<U, M>) {
+        let id = self.next_message_id;
+        self.next_message_id += 1;
+
+        for peer in self.connected_peers.clone() {
+            let Some(data) = self.app.get_users_mut().get(&peer).and_then(|user| payload(&peer, user)) else {
+                continue;
+            };
+
+            let channel = self.route(id, &data);
+            let packet = match bincode::serialize(&WirePacket::Message(PackedMessage {
+                id,
+                data,
+                must_ack: false,
+                trace_id: None,
+                origin: None,
+                exactly_once: false,
+            })) {
+                Ok(packet) => packet.into_boxed_slice(),
+                Err(e) => {
+                    net_log!(self, Level::Warn, "Failed to serialize packet: {e}");
+                    self.record_dropped(SendError::Serialize(e.to_string()));
+                    continue;
+                }
+            };
+
+            self.app.on_wire_out(id, peer, &packet);
+            match self.send_packet(channel, peer, packet) {
+                Ok(()) => self.stats.record_sent(peer),
+                Err(e) => {
+                    net_log!(self, Level::Warn, "Failed to send message {id}: {e}");
+                    self.record_dropped(e);
+                }
+            }
+        }
+    }
+
+    fn send_packet(&mut self, channel: usize, to_peer: PeerId, packet: Packet) -> Result<(), SendError> {
+        let packet = match self.signer.as_mut() {
+            Some(signer) => {
+                let signature = signer.sign(to_peer, &packet);
+                bincode::serialize(&WirePacket::<M>::Signed { payload: packet.into_vec(), signature })
+                    .map_err(|e| SendError::Serialize(e.to_string()))?
+                    .into_boxed_slice()
+            }
+            None => packet,
+        };
+        let packet = self.compression.compress(&packet);
+        // Numbered before it's split up, so its fragments can arrive in any order and still make up one packet
+        let sequenced = self.sequenced_channels.contains(&channel);
+        let packet = match sequenced {
+            true => Self::sequence(self.next_seq.get(&(to_peer, channel)).copied().unwrap_or(0), packet)?,
+            false => packet,
+        };
+        let packets = match self.fragment_threshold {
+            Some(threshold) if packet.len() > threshold => self.fragment(&packet, threshold)?,
+            _ => vec![packet],
+        };
+        for packet in packets {
+            let len = packet.len();
+            let info = self.channels.iter().find(|info| info.index == channel);
+            match (self.conditions.as_mut(), info) {
+                (Some(link), Some(info)) => {
+                    link.push_outgoing(self.elapsed, info, to_peer, packet);
+                    Ok(())
+                }
+                _ => Self::try_send(&mut self.socket, channel, to_peer, packet),
+            }?;
+            self.stats.record_bytes_sent(to_peer, len);
+        }
+        // Only numbers of whole packets that reached the socket are used up, a gap would hold back everything
+        // after it
+        if sequenced {
+            *self.next_seq.entry((to_peer, channel)).or_default() += 1;
+        }
+        Ok(())
+    }
+
+    /// Splits `packet` into fragments of at most `threshold` bytes each.
+    fn fragment(&mut self, packet: &[u8], threshold: usize) -> Result<Vec<Packet>, SendError> {
+        let empty = WirePacket::<M>::Fragment { group: 0, index: 0, count: 0, data: Vec::new() };
+        // Plus the compression header
+        let overhead = bincode::serialized_size(&empty).map_err(|e| SendError::Serialize(e.to_string()))? as usize + 1;
+        let chunks: Vec<_> = packet.chunks(threshold.saturating_sub(overhead).max(1)).collect();
+
+        let group = self.next_fragment_group;
+        self.next_fragment_group += 1;
+        chunks.iter()
+            .enumerate()
+            .map(|(index, chunk)| {
+                let fragment = WirePacket::<M>::Fragment {
+                    group,
+                    index: index as u32,
+                    count: chunks.len() as u32,
+                    data: chunk.to_vec(),
+                };
+                // Already compressed as part of the whole packet
+                bincode::serialize(&fragment)
+                    .map(|fragment| Compression::None.compress(&fragment))
+                    .map_err(|e| SendError::Serialize(e.to_string()))
+            })
+            .collect()
+    }
+
+    /// Wraps `packet` as number `seq` on a sequenced channel.
+    fn sequence(seq: u64, packet: Packet) -> Result<Packet, SendError> {
+        // The packet inside is already compressed
+        bincode::serialize(&WirePacket::<M>::Sequenced { seq, packet: packet.into_vec() })
+            .map(|sequenced| Compression::None.compress(&sequenced))
+            .map_err(|e| SendError::Serialize(e.to_string()))
+    }
+
+    fn try_send(socket: &mut WebRtcSocket, channel: usize, to_peer: PeerId, packet: Packet) -> Result<(), SendError> {
+        socket.channel_mut(channel)
+            .try_send(packet, to_peer)
+            .map_err(|e| match e.is_full() {
+                true => SendError::BufferFull { peer: to_peer },
+                false => SendError::ChannelClosed { peer: to_peer },
+            })
+    }
+
+    /// Decompresses and deserializes a received packet, checking its signature first if we have a signer.
+    fn unpack(&mut self, from_peer: PeerId, packet: &[u8]) -> Option<WirePacket<M>> {
+        let packet = match Compression::decompress(packet) {
+            Ok(packet) => packet,
+            Err(e) => {
+                net_log!(self, Level::Warn, "Failed to decompress packet: {e}");
+                self.tick_errors.push(NetworkError::Deserialize { peer: from_peer, error: e.to_string() });
+                return None;
+            }
+        };
+        let incoming: WirePacket<M> = match bincode::deserialize_from(&packet[..]) {
+            Ok(packet) => packet,
+            Err(e) => {
+                net_log!(self, Level::Warn, "Failed to deserialize packet: {e}");
+                self.tick_errors.push(NetworkError::Deserialize { peer: from_peer, error: e.to_string() });
+                return None;
+            }
+        };
+
+        // Fragments and sequenced packets aren't signed on their own, the packets they carry are
+        let wrapper = matches!(incoming, WirePacket::Fragment { .. } | WirePacket::Sequenced { .. });
+        let Some(signer) = self.signer.as_mut().filter(|_| !wrapper) else {
+            return Some(incoming);
+        };
+
+        let payload = match incoming {
+            WirePacket::Signed { payload, signature } if signer.verify(from_peer, &payload, &signature) => payload,
+            _ => {
+                net_log!(self, Level::Warn, "Dropping packet from {from_peer} without a valid signature");
+                self.stats.signature_invalid += 1;
+                self.app.on_signature_invalid(from_peer);
+                return None;
+            }
+        };
+
+        match bincode::deserialize_from(&payload[..]) {
+            // A signed packet only ever wraps a plain one
+            Ok(WirePacket::Signed { .. }) => None,
+            Ok(packet) => Some(packet),
+            Err(e) => {
+                net_log!(self, Level::Warn, "Failed to deserialize signed packet: {e}");
+                self.tick_errors.push(NetworkError::Deserialize { peer: from_peer, error: e.to_string() });
+                None
+            }
+        }
+    }
+
+    /// Keeps packets received while paused for later, as long as there's room.
+    fn hold_incoming(&mut self, received: Vec<(usize, PeerId, Packet)>) {
+        for (channel, from_peer, packet) in received {
+            if self.ejected.contains(&from_peer) {
+                continue;
+            }
+            // They're still talking to us even if we're not listening
+            self.last_seen.insert(from_peer, self.elapsed);
+
+            if self.held_incoming.len() >= self.pause_capacity {
+                net_log!(self, Level::Warn, "Dropping packet from {from_peer} received while paused, already holding {}", self.held_incoming.len());
+                self.stats.dropped_while_paused += 1;
+                continue;
+            }
+            self.held_incoming.push((channel, from_peer, packet));
+        }
+    }
+
+    /// Handles packets received this tick, in order, returning the acks owed for them by peer and channel.
+    /// Packets marked released came out of the sequencer and were already seen on the wire.
+    fn handle_incoming(
+        &mut self,
+        mut incoming_packets: VecDeque<(usize, PeerId, Packet, bool)>,
+    ) -> HashMap<(PeerId, usize), Vec<PackedAck<M>>> {
+        let mut acks_to_send: HashMap<(PeerId, usize), Vec<PackedAck<M>>> = HashMap::new();
+        while let Some((channel, from_peer, packet, released)) = incoming_packets.pop_front() {
+            if self.ejected.contains(&from_peer) {
+                continue;
+            }
+            self.last_seen.insert(from_peer, self.elapsed);
+
+            if !released {
+                self.app.on_wire_in(from_peer, &packet);
+            }
+            let Some(mut incoming) = self.unpack(from_peer, &packet) else {
+                continue;
+            };
+            // Fragments carry a whole packet, which may be sequenced, while sequenced packets carry plain ones
+            if !released {
+                if let WirePacket::Fragment { group, index, count, data } = incoming {
+                    let Some(whole) = self.reassembly.insert(from_peer, group, index, count, data, self.elapsed) else {
+                        continue;
+                    };
+                    let Some(unpacked) = self.unpack(from_peer, &whole) else {
+                        continue;
+                    };
+                    incoming = unpacked;
+                }
+                if let WirePacket::Sequenced { seq, packet } = incoming {
+                    // Whatever was held back behind this one goes next, in order
+                    let hold = self.channels.iter().any(|info| info.index == channel && info.reliable);
+                    let ready = self.sequencer.insert(from_peer, channel, seq, packet, hold, self.elapsed);
+                    for packet in ready.into_iter().rev() {
+                        incoming_packets.push_front((channel, from_peer, packet.into_boxed_slice(), true));
+                    }
+                    continue;
+                }
+            }
+
+            match incoming {
+                WirePacket::AckBatch(acks) => {
+                    for ack in acks {
+                        self.handle_ack(from_peer, ack);
+                    }
+                }
+                WirePacket::Message(incoming_message) => {
+                    self.stats.record_received(from_peer);
+                    let ctx = ReceiveContext {
+                        id: incoming_message.id,
+                        from_peer,
+                        channel,
+                        trace_id: incoming_message.trace_id,
+                        origin: incoming_message.origin,
+                    };
+
+                    let exactly_once = incoming_message.exactly_once || self.dedup_all;
+                    if exactly_once {
+                        if let Some(response) = self.dedup.get(&from_peer, incoming_message.id) {
+                            // Seen it already, but they may not have gotten our ack
+                            if let Some(response) = response.cloned() {
+                                acks_to_send.entry((from_peer, channel)).or_default().push(PackedAck {
+                                    id: incoming_message.id,
+                                    data: response,
+                                });
+                            }
+                            self.stats.duplicates += 1;
+                            continue;
+                        }
+                    }
+
+                    let outcome = self.dispatch_receive(ctx, incoming_message.must_ack, &incoming_message.data);
+                    let mut acked_with = None;
+                    if incoming_message.must_ack {
+                        let response = self.app.ack_response(ctx, &incoming_message.data, outcome);
+                        acked_with = Some(response.clone());
+                        acks_to_send.entry((from_peer, channel)).or_default().push(PackedAck {
+                            id: incoming_message.id,
+                            data: response,
+                        });
+                    }
+                    if exactly_once {
+                        self.dedup.record(from_peer, incoming_message.id, acked_with);
+                    }
+                    self.feed_streams(ctx, incoming_message.must_ack, &incoming_message.data);
+                }
+                WirePacket::StreamChunk { stream, data } => self.app.on_stream_chunk(from_peer, stream, &data),
+                WirePacket::StreamEnd { stream, aborted: false } => self.app.on_stream_complete(from_peer, stream),
+                WirePacket::StreamEnd { stream, aborted: true } => self.app.on_stream_aborted(from_peer, stream),
+                // Hearing from them was the point, that's already noted
+                WirePacket::Heartbeat => {}
+                WirePacket::Fragment { .. } => {
+                    net_log!(self, Level::Warn, "Ignoring a fragment from {from_peer} inside another packet");
+                }
+                WirePacket::Sequenced { .. } => {
+                    net_log!(self, Level::Warn, "Ignoring a sequenced packet from {from_peer} inside another");
+                }
+                WirePacket::Hello(meta) => match self.awaiting_hello.remove(&from_peer) {
+                    Some(_) => self.admit(from_peer, Some(&meta)),
+                    None => net_log!(self, Level::Debug, "Ignoring hello from {from_peer}, we weren't waiting on one"),
+                },
+                WirePacket::Goodbye => {
+                    net_log!(self, Level::Info, "Peer left: {from_peer}");
+                    self.eject(from_peer, DisconnectReason::Left);
+                }
+                WirePacket::Signed { .. } => {
+                    net_log!(self, Level::Warn, "Ignoring signed packet from {from_peer}, we have no signer to check it with");
+                }
+            }
+        }
+
+        acks_to_send
+    }
+
+    fn receive_packets(&mut self, channel: usize) -> Vec<(PeerId, Packet)> {
+        let packets = self.socket.channel_mut(channel).receive();
+        for (from_peer, packet) in &packets {
+            self.stats.record_bytes_received(*from_peer, packet.len());
+        }
+        let info = self.channels.iter().find(|info| info.index == channel);
+        match (self.conditions.as_mut(), info) {
+            (Some(link), Some(info)) => {
+                for (from_peer, packet) in packets {
+                    link.push_incoming(self.elapsed, info, from_peer, packet);
+                }
+                link.take_due_incoming(self.elapsed, channel)
+                    .into_iter()
+                    .map(|delayed| (delayed.peer, delayed.packet))
+                    .collect()
+            }
+            _ => packets,
+        }
+    }
+
+    fn feed_streams(&mut self, ctx: ReceiveContext, must_ack: bool, data: &M) {
+        if self.streams.is_empty() {
+            return;
+        }
+        self.streams.retain(|stream| stream.is_connected());
+        for stream in &self.streams {
+            stream.push(IncomingMessage { ctx, must_ack, data: data.clone() });
+        }
+    }
+
+    /// Hands a received message to the app, or keeps it for `drain_received`, and says what the app did with it.
+    fn dispatch_receive(&mut self, ctx: ReceiveContext, must_ack: bool, message: &M) -> ReceiveOutcome {
+        if let Some(trace_id) = ctx.trace_id {
+            net_log!(self, Level::Debug, "Received message {} from {} with trace id {trace_id:032x}", ctx.id, ctx.from_peer);
+        }
+
+        if let Some(received) = self.received.as_mut() {
+            received.push(IncomingMessage { ctx, must_ack, data: message.clone() });
+            return ReceiveOutcome::Handled;
+        }
+
+        let user = match self.user_dispatch {
+            true => self.app.get_users_mut().remove(&ctx.from_peer),
+            false => None,
+        };
+
+        let outcome = match user {
+            Some(mut user) => {
+                let outcome = self.app.receive_from_user(ctx, &mut user, message);
+                self.app.get_users_mut().insert(ctx.from_peer, user);
+                outcome
+            }
+            None => self.app.receive_with_context(ctx, message),
+        };
+
+        if outcome == ReceiveOutcome::Unhandled {
+            self.stats.unhandled += 1;
+            self.app.on_unhandled(ctx.id, ctx.from_peer, message);
+        }
+        outcome
+    }
+
+    /// Marks a pending message as acked by `from_peer`, calling its handler(s) once everyone has responded.
+    fn handle_ack(&mut self, from_peer: PeerId, ack: PackedAck<M>) {
+        let Some(unacked) = self.messages_waiting_for_ack.get_mut(&ack.id) else {
+            self.stats.orphan_acks += 1;
+            self.app.on_orphan_ack(ack.id, from_peer, &ack.data);
+            return;
+        };
+
+        // Retransmitted acks shouldn't count twice
+        if unacked.has_acked(&from_peer) {
+            return;
+        }
+        unacked.responses.push((from_peer, ack.data));
+
+        let sample = self.elapsed.saturating_sub(unacked.sent_at);
+        let rtt = match self.rtt.get(&from_peer) {
+            Some(rtt) => rtt.mul_f64(1.0 - RTT_SMOOTHING) + sample.mul_f64(RTT_SMOOTHING),
+            None => sample,
+        };
+        self.rtt.insert(from_peer, rtt);
+        if let Some(receipts) = self.receipts.as_mut() {
+            receipts.push(DeliveryReceipt { id: ack.id, peer: from_peer, acked_at: self.elapsed, round_trip: sample });
+        }
+
+        // If all peers have acked, call the handler(s)
+        if unacked.is_complete() {
+            self.complete_ack(ack.id);
+        }
+    }
+
+    /// Calls the handler(s) of a message that everyone has acked, and forgets about it.
+    fn complete_ack(&mut self, id: MessageId) {
+        let Some(mut unacked) = self.messages_waiting_for_ack.remove(&id) else {
+            return;
+        };
+
+        if self.recently_acked.len() == RECENTLY_ACKED_CAPACITY {
+            self.recently_acked.pop_front();
+        }
+        self.recently_acked.push_back(id);
+
+        match unacked.message.ack_handler.as_mut() {
+            // For broadcasted messages, this calls the handler once for each peer
+            Some(AckCallback::PerPeer(handler)) => {
+                for (peer, response) in unacked.responses.iter() {
+                    handler(&mut self.app, id, *peer, response);
+                }
+            }
+            Some(AckCallback::PerUser(handler)) => {
+                for (peer, response) in unacked.responses.iter() {
+                    let Some(mut user) = self.app.get_users_mut().remove(peer) else {
+                        net_log!(self, Level::Debug, "Skipping ack handler of message {id} for {peer}, they have no user anymore");
+                        continue;
+                    };
+                    handler(&mut self.app, &mut user, id, *peer, response);
+                    self.app.get_users_mut().insert(*peer, user);
+                }
+            }
+            Some(AckCallback::Broadcast(handler)) => {
+                let acked_peers: Vec<_> = unacked.responses.iter().map(|(peer, _)| *peer).collect();
+                handler(&mut self.app, id, &acked_peers, &unacked.responses);
+            }
+            None => {}
+        }
+    }
+
+    /// Completes every message that is no longer waiting on anyone, e.g. because the peers it was waiting on left.
+    fn complete_finished_acks(&mut self) {
+        let finished: Vec<_> = self.messages_waiting_for_ack.iter()
+            .filter(|(_, unacked)| unacked.is_complete())
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in finished {
+            self.complete_ack(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+    use super::*;
+
+    type TestManager = NetworkManager<DefaultUser, MinimalApp<String>, String>;
+
+    fn peer(n: u128) -> PeerId {
+        PeerId(Uuid::from_u128(n))
+    }
+
+    fn manager() -> TestManager {
+        let (socket, _message_loop) = WebRtcSocket::new_reliable("ws://localhost:3536/test");
+        NetworkManager::new_minimal(socket)
+    }
+
+    /// A manager whose outgoing packets are kept for `take_sent` instead of going to the socket.
+    fn capturing_manager(reliable: bool) -> TestManager {
+        let (socket, _message_loop) = match reliable {
+            true => WebRtcSocket::new_reliable("ws://localhost:3536/test"),
+            false => WebRtcSocket::new_unreliable("ws://localhost:3536/test"),
+        };
+        NetworkManager::new_minimal(socket).with_network_conditions(NetworkConditions::default())
+    }
+
+    /// Everything `network` put on the wire so far.
+    fn take_sent(network: &mut TestManager) -> Vec<Packet> {
+        let link = network.conditions.as_mut().unwrap();
+        link.take_due_outgoing(network.elapsed).into_iter().map(|delayed| delayed.packet).collect()
+    }
+
+    /// Hands `packets` to `network` as received from `from_peer` on channel 0, and returns the messages it
+    /// delivered.
+    fn deliver(network: &mut TestManager, from_peer: PeerId, packets: Vec<Packet>) -> Vec<String> {
+        let incoming = packets.into_iter().map(|packet| (0, from_peer, packet, false)).collect();
+        network.handle_incoming(incoming);
+        network.drain_events()
+            .into_iter()
+            .filter_map(|event| match event {
+                NetworkEvent::Message { data, .. } => Some(data),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Sends `data` from `sender` to `to_peer` on channel 0 the way `tick` does.
+    fn send(sender: &mut TestManager, to_peer: PeerId, data: &str) {
+        let packet = TestManager::pack(&Message::new(data.to_string())).unwrap();
+        sender.send_packet(0, to_peer, packet).unwrap();
+    }
+
+    /// Sends message `id` to `recipients` as far as the ack bookkeeping is concerned.
+    fn wait_for_acks(network: &mut TestManager, id: MessageId, recipients: &[PeerId]) {
+        let message = Message::new(format!("message {id}"))
+            .to_peers(recipients.iter().copied())
+            .ack_deadline(Duration::from_secs(60));
+        network.messages_waiting_for_ack.insert(id, MessageWaitingForAck {
+            message,
+            recipients: recipients.to_vec(),
+            responses: Vec::new(),
+            sent_at: Duration::ZERO,
+            last_sent_at: Duration::ZERO,
+            retries: 0,
+            fanning_out: false,
+        });
+    }
+
+    /// Puts `ids` in a single ack batch from `from_peer` and hands it to the manager the way `tick` does.
+    fn receive_ack_batch(network: &mut TestManager, from_peer: PeerId, ids: &[MessageId]) {
+        let acks = ids.iter()
+            .map(|id| PackedAck { id: *id, data: AckResult::Ack(Some(format!("ack {id}"))) })
+            .collect();
+        let packet = bincode::serialize(&WirePacket::AckBatch(acks)).unwrap();
+        let packet = network.compression.compress(&packet);
+        match network.unpack(from_peer, &packet) {
+            Some(WirePacket::AckBatch(acks)) => {
+                for ack in acks {
+                    network.handle_ack(from_peer, ack);
+                }
+            }
+            _ => panic!("expected an ack batch"),
+        }
+    }
+
+    /// An app that handles nothing, noting what reaches `on_unhandled`.
+    struct UnhandlingApp {
+        users: UserList<DefaultUser>,
+        message_queue: MessageQueue<DefaultUser, UnhandlingApp, String>,
+        unhandled: Vec<MessageId>,
+    }
+
+    impl TApp<DefaultUser> for UnhandlingApp {
+        type Application = UnhandlingApp;
+        type Message = String;
+
+        fn users(&mut self) -> &mut UserList<DefaultUser> {
+            &mut self.users
+        }
+
+        fn message_queue(&mut self) -> &mut MessageQueue<DefaultUser, Self::Application, Self::Message> {
+            &mut self.message_queue
+        }
+
+        fn receive(&mut self, _id: MessageId, _from_peer: PeerId, _message: &Self::Message) -> ReceiveOutcome {
+            ReceiveOutcome::Unhandled
+        }
+
+        fn tick(&mut self, _delta: Duration) {}
+
+        fn on_unhandled(&mut self, id: MessageId, _from_peer: PeerId, _message: &Self::Message) {
+            self.unhandled.push(id);
+        }
+    }
+
+    /// What `peer` puts on the wire for message `id` asking for an ack.
+    fn must_ack_packet(id: MessageId) -> Packet {
+        let mut message = Message::new(format!("message {id}")).ack_deadline(Duration::from_secs(60));
+        message.id = id;
+        let packet = TestManager::pack(&message).unwrap();
+        Compression::None.compress(&packet)
+    }
+
+    #[test]
+    fn unhandled_must_ack_messages_reach_on_unhandled() {
+        let (socket, _message_loop) = WebRtcSocket::new_reliable("ws://localhost:3536/test");
+        let app = UnhandlingApp { users: UserList::new(), message_queue: MessageQueue::new(), unhandled: Vec::new() };
+        let mut network = NetworkManager::new(socket, app);
+
+        let acks = network.handle_incoming(VecDeque::from([(0, peer(1), must_ack_packet(4), false)]));
+        assert_eq!(network.app.unhandled, vec![4]);
+        assert_eq!(network.stats.unhandled, 1);
+        let ack = &acks[&(peer(1), 0)][..];
+        assert!(matches!(ack, [PackedAck { id: 4, data: AckResult::Ack(None) }]));
+    }
+
+    #[test]
+    fn minimal_managers_ack_with_nothing() {
+        let mut network = manager();
+        let acks = network.handle_incoming(VecDeque::from([(0, peer(1), must_ack_packet(4), false)]));
+        assert!(matches!(&acks[&(peer(1), 0)][..], [PackedAck { id: 4, data: AckResult::Ack(None) }]));
+        assert_eq!(deliver(&mut network, peer(1), Vec::new()), vec!["message 4".to_string()]);
+    }
+
+    #[test]
+    fn buffers_must_ack_messages_and_acks_them() {
+        let mut network = manager().with_received_buffer();
+        let acks = network.handle_incoming(VecDeque::from([(0, peer(1), must_ack_packet(4), false)]));
+        assert_eq!(acks[&(peer(1), 0)].len(), 1);
+
+        let mut received = Vec::new();
+        network.drain_received(&mut received);
+        assert_eq!(received.len(), 1);
+        assert!(received[0].must_ack);
+        assert_eq!(received[0].data, "message 4");
+    }
+
+    #[test]
+    fn fragments_packets_on_sequenced_channels() {
+        for reliable in [true, false] {
+            let sequenced = |network: TestManager| {
+                network.with_fragment_threshold(MIN_FRAGMENT_THRESHOLD).with_channel_ordering(0, ChannelOrdering::Sequenced)
+            };
+            let mut sender = sequenced(capturing_manager(reliable));
+            let mut receiver = sequenced(capturing_manager(reliable));
+            let big = "trailrunner ".repeat(200);
+
+            send(&mut sender, peer(2), &big);
+            let mut fragments = take_sent(&mut sender);
+            assert!(fragments.len() > 1);
+            assert!(fragments.iter().all(|fragment| fragment.len() <= MIN_FRAGMENT_THRESHOLD));
+            // Fragments may arrive in any order, even where late packets are dropped
+            fragments.reverse();
+            assert_eq!(deliver(&mut receiver, peer(1), fragments), vec![big.clone()]);
+
+            // The next packet is next in sequence, and the one after it is held back or dropped as usual
+            send(&mut sender, peer(2), "second");
+            send(&mut sender, peer(2), "third");
+            let mut packets = take_sent(&mut sender);
+            packets.reverse();
+            let expected = match reliable {
+                true => vec!["second".to_string(), "third".to_string()],
+                false => vec!["third".to_string()],
+            };
+            assert_eq!(deliver(&mut receiver, peer(1), packets), expected);
+        }
+    }
+
+    /// Signs with a hash of the payload and a key, so only managers with the same key accept each other's packets.
+    struct KeyedSigner(u64);
+
+    impl TSigner for KeyedSigner {
+        fn sign(&mut self, _to_peer: PeerId, payload: &[u8]) -> Vec<u8> {
+            let mut hasher = DefaultHasher::new();
+            (self.0, payload).hash(&mut hasher);
+            hasher.finish().to_le_bytes().to_vec()
+        }
+
+        fn verify(&mut self, from_peer: PeerId, payload: &[u8], signature: &[u8]) -> bool {
+            self.sign(from_peer, payload) == signature
+        }
+    }
+
+    #[test]
+    fn round_trips_fragmented_compressed_signed_packets() {
+        let configure = |network: TestManager, key: u64| {
+            #[cfg(feature = "lz4")]
+            let network = network.with_compression(Compression::Lz4);
+            network.with_fragment_threshold(MIN_FRAGMENT_THRESHOLD).with_signer(KeyedSigner(key))
+        };
+        let mut sender = configure(capturing_manager(true), 1);
+        let mut receiver = configure(capturing_manager(true), 1);
+        let mut impostor = configure(capturing_manager(true), 2);
+        let data = (0..2000).map(|i| i.to_string()).collect::<Vec<_>>().join(",");
+
+        send(&mut sender, peer(2), &data);
+        let mut fragments = take_sent(&mut sender);
+        assert!(fragments.len() > 1);
+        #[cfg(feature = "lz4")]
+        assert!(fragments.iter().map(|fragment| fragment.len()).sum::<usize>() < data.len());
+        fragments.reverse();
+        assert_eq!(deliver(&mut receiver, peer(1), fragments.clone()), vec![data]);
+        assert_eq!(receiver.stats.signature_invalid, 0);
+
+        assert!(deliver(&mut impostor, peer(1), fragments).is_empty());
+        assert_eq!(impostor.stats.signature_invalid, 1);
+    }
+
+    /// Queues a broadcast to `peers` asking for acks, spread out to `rate` of them per tick.
+    fn fan_out(network: &mut TestManager, peers: &[PeerId], rate: usize) -> Message<DefaultUser, MinimalApp<String>, String> {
+        network.connected_peers = peers.to_vec();
+        Message::new("fanned out".to_string()).with_fanout_rate(rate).ack_deadline(Duration::from_secs(60))
+    }
+
+    #[test]
+    fn fanouts_wait_only_on_peers_sent_to() {
+        let mut network = capturing_manager(true);
+        let peers = [peer(1), peer(2), peer(3), peer(4)];
+        let message = fan_out(&mut network, &peers, 2);
+        network.app.message_queue().enqueue(message).unwrap();
+
+        network.send_queued();
+        assert_eq!(take_sent(&mut network).len(), 2);
+        receive_ack_batch(&mut network, peer(1), &[0]);
+        receive_ack_batch(&mut network, peer(2), &[0]);
+        assert_eq!(network.ack_status(0), AckStatus::Pending { acked: 2, total: 2 });
+
+        network.continue_fanouts();
+        assert_eq!(take_sent(&mut network).len(), 2);
+        assert_eq!(network.ack_status(0), AckStatus::Pending { acked: 2, total: 4 });
+        receive_ack_batch(&mut network, peer(3), &[0]);
+        receive_ack_batch(&mut network, peer(4), &[0]);
+        assert_eq!(network.ack_status(0), AckStatus::Complete);
+    }
+
+    #[test]
+    fn fanouts_send_again_at_their_rate() {
+        let mut network = capturing_manager(true);
+        let peers = [peer(1), peer(2), peer(3), peer(4)];
+        let message = fan_out(&mut network, &peers, 2).with_ack_timeout(Duration::from_secs(1));
+        network.app.message_queue().enqueue(message).unwrap();
+        network.send_queued();
+        network.continue_fanouts();
+        assert_eq!(take_sent(&mut network).len(), 4);
+
+        network.elapsed = Duration::from_secs(1);
+        network.retry_unacked();
+        network.continue_fanouts();
+        assert_eq!(take_sent(&mut network).len(), 2);
+        network.continue_fanouts();
+        assert_eq!(take_sent(&mut network).len(), 2);
+        network.continue_fanouts();
+        assert!(take_sent(&mut network).is_empty());
+    }
+
+    #[test]
+    fn fanouts_report_failures_of_every_batch() {
+        // Without its message loop the socket fails every send
+        let mut network = manager();
+        let peers = [peer(1), peer(2), peer(3), peer(4)];
+        let failures = std::rc::Rc::new(std::cell::Cell::new(0));
+        let counted = failures.clone();
+        let message = fan_out(&mut network, &peers, 2).on_send_error(move |_, _| counted.set(counted.get() + 1));
+        network.app.message_queue().enqueue(message).unwrap();
+
+        network.send_queued();
+        assert_eq!(failures.get(), 2);
+        network.continue_fanouts();
+        assert_eq!(failures.get(), 4);
+        // It reached nobody, so there's nothing to wait on
+        assert!(network.messages_waiting_for_ack.is_empty());
+    }
+
+    #[test]
+    fn does_not_wait_on_messages_that_reached_nobody() {
+        let mut network = manager();
+        network.connected_peers = vec![peer(1), peer(2)];
+        let message = Message::new("lost".to_string()).ack_deadline(Duration::from_secs(60));
+        network.app.message_queue().enqueue(message).unwrap();
+        network.send_queued();
+        assert!(network.messages_waiting_for_ack.is_empty());
+        assert_eq!(network.stats.dropped.channel_closed, 2);
+    }
+
+    #[test]
+    fn payload_size_is_the_serialized_message() {
+        let mut network = capturing_manager(true);
+        let data = "trailrunner".to_string();
+        let size = network.payload_size(&data).unwrap();
+        assert_eq!(size, TestManager::pack(&Message::new(data.clone())).unwrap().len());
+
+        // Uncompressed, the wire only adds the compression header
+        send(&mut network, peer(1), &data);
+        assert_eq!(take_sent(&mut network)[0].len(), size + 1);
+    }
+
+    #[test]
+    fn one_batch_acks_every_message_in_it() {
+        let mut network = manager();
+        for id in 0..3 {
+            wait_for_acks(&mut network, id, &[peer(1)]);
         }
+        receive_ack_batch(&mut network, peer(1), &[0, 2]);
+        assert_eq!(network.ack_status(0), AckStatus::Complete);
+        assert_eq!(network.ack_status(1), AckStatus::Pending { acked: 0, total: 1 });
+        assert_eq!(network.ack_status(2), AckStatus::Complete);
+    }
+
+    #[test]
+    fn batches_complete_a_broadcast_once_everyone_acked() {
+        let mut network = manager();
+        wait_for_acks(&mut network, 0, &[peer(1), peer(2)]);
+        receive_ack_batch(&mut network, peer(1), &[0]);
+        assert_eq!(network.ack_status(0), AckStatus::Pending { acked: 1, total: 2 });
+        receive_ack_batch(&mut network, peer(2), &[0]);
+        assert_eq!(network.ack_status(0), AckStatus::Complete);
+    }
+
+    #[test]
+    fn repeated_and_unknown_acks_in_a_batch_count_once() {
+        let mut network = manager();
+        wait_for_acks(&mut network, 0, &[peer(1), peer(2)]);
+        receive_ack_batch(&mut network, peer(1), &[0, 0, 7]);
+        assert_eq!(network.ack_status(0), AckStatus::Pending { acked: 1, total: 2 });
+        assert_eq!(network.stats.orphan_acks, 1);
+    }
+
+    #[test]
+    fn migrates_users_that_already_left() {
+        let mut network = manager();
+        network.app.get_users_mut().insert(peer(1), DefaultUser::new(peer(1)));
+        network.eject(peer(1), DisconnectReason::Left);
+        assert!(network.app.get_users_mut().get(&peer(1)).is_none());
+
+        assert!(network.migrate_user(peer(1), peer(2)));
+        assert!(network.app.get_users_mut().get(&peer(2)).is_some());
+        assert!(network.app.get_users_mut().get(&peer(1)).is_none());
+        assert!(!network.migrate_user(peer(1), peer(3)));
+    }
+
+    #[test]
+    fn does_not_migrate_kicked_users() {
+        let mut network = manager();
+        network.app.get_users_mut().insert(peer(1), DefaultUser::new(peer(1)));
+        network.eject(peer(1), DisconnectReason::Kicked);
+        assert!(!network.migrate_user(peer(1), peer(2)));
+    }
+
+    #[test]
+    fn recognises_rejoins_under_a_new_id_by_reconnect_key() {
+        let mut network = manager().with_reconnect_dedup(|_, _| Some("account"));
+        network.app.get_users_mut().insert(peer(1), DefaultUser::new(peer(1)));
+        network.eject(peer(1), DisconnectReason::Left);
+        assert_eq!(network.departed_keys.len(), 1);
+
+        network.app.get_users_mut().insert(peer(2), DefaultUser::new(peer(2)));
+        network.report_rejoin(peer(2));
+        assert!(network.departed_keys.is_empty());
+    }
+
+    #[test]
+    fn fails_messages_to_peers_that_left_straight_away() {
+        let mut network = manager();
+        network.remember_departure(peer(1));
+        network.send(peer(1), "hello".to_string()).unwrap();
+        network.send_queued();
+        assert!(network.pending_sends.is_empty());
+        assert_eq!(network.stats.dropped.no_such_peer, 1);
+    }
+
+    #[test]
+    fn drops_messages_held_for_peers_that_leave() {
+        let mut network = manager();
+        network.send(peer(1), "hello".to_string()).unwrap();
+        network.send_queued();
+        assert_eq!(network.pending_sends.len(), 1);
+
+        network.remember_departure(peer(1));
+        network.send_queued();
+        assert!(network.pending_sends.is_empty());
+        assert_eq!(network.stats.dropped.no_such_peer, 1);
+    }
+
+    #[test]
+    fn recognises_rejoins_by_peer_id_without_a_key() {
+        let mut network = manager();
+        network.app.get_users_mut().insert(peer(1), DefaultUser::new(peer(1)));
+        network.eject(peer(1), DisconnectReason::Left);
+        assert!(network.departed_keys.is_empty());
 
-        self.app.tick(delta);
+        network.report_rejoin(peer(2));
+        assert!(network.departed.contains_key(&peer(1)));
+        network.report_rejoin(peer(1));
+        assert!(network.departed.is_empty());
     }
 }
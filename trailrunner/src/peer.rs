@@ -0,0 +1,37 @@
+use matchbox_socket::PeerId;
+use uuid::Uuid;
+use crate::error::PeerIdParseError;
+
+/// The canonical text form of a peer id: a lowercase hyphenated UUID such as
+/// `67e55044-10b1-426f-9247-bb680e5fe0c8`. It's the same as the id's `Display` output and is stable across
+/// versions, so it's safe to log, persist, and use as a key to recognise a peer again later.
+///
+/// ```
+/// use trailrunner::prelude::*;
+///
+/// let text = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+/// let peer = peer_id_from_str(text).unwrap();
+/// assert_eq!(peer_id_to_string(&peer), text);
+/// assert!(peer_id_from_str("not a peer").is_err());
+/// ```
+pub fn peer_id_to_string(peer: &PeerId) -> String {
+    peer.0.hyphenated().to_string()
+}
+
+/// Parses a peer id back from `peer_id_to_string`. Any UUID form is accepted (hyphenated or not, either
+/// case), anything else is an error.
+pub fn peer_id_from_str(s: &str) -> Result<PeerId, PeerIdParseError> {
+    Uuid::parse_str(s.trim())
+        .map(PeerId)
+        .map_err(|_| PeerIdParseError { input: s.to_string() })
+}
+
+/// The 16 raw bytes of a peer id, big endian, for compact storage.
+pub fn peer_id_to_bytes(peer: &PeerId) -> [u8; 16] {
+    *peer.0.as_bytes()
+}
+
+/// The inverse of `peer_id_to_bytes`.
+pub fn peer_id_from_bytes(bytes: [u8; 16]) -> PeerId {
+    PeerId(Uuid::from_bytes(bytes))
+}
@@ -0,0 +1,133 @@
+use std::fmt;
+use std::time::Duration;
+use matchbox_socket::{PeerId, WebRtcSocket};
+use serde::de::{Deserializer, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+use crate::prelude::*;
+
+/// A message body sent as the bytes it holds, for `RawNetworkManager`. It goes into the packet as a single
+/// block of bytes behind its length, without being serialized element by element like a `Vec<u8>` would.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RawPayload(pub Vec<u8>);
+
+impl Serialize for RawPayload {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for RawPayload {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_byte_buf(RawPayloadVisitor)
+    }
+}
+
+struct RawPayloadVisitor;
+
+impl<'de> Visitor<'de> for RawPayloadVisitor {
+    type Value = RawPayload;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a byte buffer")
+    }
+
+    fn visit_bytes<E>(self, bytes: &[u8]) -> Result<RawPayload, E> {
+        Ok(RawPayload(bytes.to_vec()))
+    }
+
+    fn visit_byte_buf<E>(self, bytes: Vec<u8>) -> Result<RawPayload, E> {
+        Ok(RawPayload(bytes))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<RawPayload, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(RawPayload(bytes))
+    }
+}
+
+/// Something that happened during a tick, as `RawNetworkManager::drain_events` returns it.
+pub type RawNetworkEvent = NetworkEvent<Vec<u8>>;
+
+/// A manager that isn't generic over anything, sending and receiving plain byte vectors, for exposing over
+/// FFI or a plugin boundary. The caller serializes payloads themselves, the bytes are handed over as they
+/// were sent. Routing, acks and channels work as for any other manager, configure it with the usual
+/// `with_*` methods before wrapping it with `from_manager`, or reach it with `manager_mut`.
+pub struct RawNetworkManager {
+    manager: NetworkManager<DefaultUser, MinimalApp<RawPayload>, RawPayload>,
+}
+
+impl RawNetworkManager {
+    pub fn new(socket: WebRtcSocket) -> Self {
+        Self::from_manager(NetworkManager::new_minimal(socket))
+    }
+
+    /// Wraps a manager that was already configured.
+    pub fn from_manager(manager: NetworkManager<DefaultUser, MinimalApp<RawPayload>, RawPayload>) -> Self {
+        Self { manager }
+    }
+
+    /// The manager underneath, for everything this doesn't cover.
+    pub fn manager(&self) -> &NetworkManager<DefaultUser, MinimalApp<RawPayload>, RawPayload> {
+        &self.manager
+    }
+
+    pub fn manager_mut(&mut self) -> &mut NetworkManager<DefaultUser, MinimalApp<RawPayload>, RawPayload> {
+        &mut self.manager
+    }
+
+    /// See `NetworkManager::tick`.
+    pub fn tick(&mut self, delta: Duration) -> Result<TickReport, NetworkError> {
+        self.manager.tick(delta)
+    }
+
+    /// Queues `data` to be sent to `peer` on the next tick.
+    pub fn send(&mut self, peer: PeerId, data: Vec<u8>) -> Result<MessageHandle, MessageError> {
+        self.manager.send(peer, RawPayload(data))
+    }
+
+    /// Queues `data` to be sent to every connected peer on the next tick.
+    pub fn broadcast(&mut self, data: Vec<u8>) -> Result<MessageHandle, MessageError> {
+        self.manager.broadcast(RawPayload(data))
+    }
+
+    /// Everything that happened since the last call.
+    pub fn drain_events(&mut self) -> Vec<RawNetworkEvent> {
+        self.manager.drain_events()
+            .into_iter()
+            .map(|event| match event {
+                NetworkEvent::PeerConnected(peer) => NetworkEvent::PeerConnected(peer),
+                NetworkEvent::PeerDisconnected(peer, reason) => NetworkEvent::PeerDisconnected(peer, reason),
+                NetworkEvent::Message { id, from_peer, data } => NetworkEvent::Message { id, from_peer, data: data.0 },
+            })
+            .collect()
+    }
+
+    pub fn connected_peers(&self) -> &[PeerId] {
+        self.manager.connected_peers()
+    }
+
+    pub fn local_peer_id(&self) -> Option<PeerId> {
+        self.manager.local_peer_id()
+    }
+
+    /// See `NetworkManager::close`.
+    pub fn close(&mut self) {
+        self.manager.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payloads_go_out_as_the_bytes_they_hold() {
+        let payload = RawPayload(vec![1, 2, 3]);
+        let encoded = bincode::serialize(&payload).unwrap();
+        assert_eq!(encoded, [&3u64.to_le_bytes()[..], &[1, 2, 3]].concat());
+        assert_eq!(bincode::deserialize::<RawPayload>(&encoded).unwrap(), payload);
+    }
+}
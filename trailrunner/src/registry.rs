@@ -0,0 +1,99 @@
+/// Ties a request type to the type of response peers answer it with.
+///
+/// Sending requests with `Message::request` and answering them with `respond` makes the compiler catch a
+/// request being handled with the wrong kind of response. Implemented for you by `message_registry!`.
+pub trait TRequest<M>: Into<M> {
+    type Response: TryFrom<M> + Into<M>;
+}
+
+/// Packs the response to `_request` into the message type, only compiling if it's the right response for it.
+pub fn respond<M, R: TRequest<M>>(_request: &R, response: R::Response) -> M {
+    response.into()
+}
+
+/// Declares a message enum with one variant per payload type, along with conversions to and from each
+/// payload and a fieldless "kind" enum that serves as a stable discriminant.
+///
+/// Variants written as `Variant(Request) => Response` also register `Request` as a `TRequest` answered with
+/// `Response`. Every payload type must be distinct, and new variants should be added at the end so existing
+/// peers keep decoding the old ones.
+///
+/// ```rust
+/// use trailrunner::prelude::*;
+///
+/// #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+/// pub struct Chat(String);
+/// #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+/// pub struct TradeOffer { gold: u32 }
+/// #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+/// pub struct TradeAnswer { accepted: bool }
+///
+/// trailrunner::message_registry! {
+///     #[derive(Debug)]
+///     pub enum GameMessage / GameMessageKind {
+///         Chat(Chat),
+///         Trade(TradeOffer) => TradeAnswer,
+///         TradeAnswer(TradeAnswer),
+///     }
+/// }
+///
+/// let offer = TradeOffer { gold: 10 };
+/// let answer: GameMessage = respond(&offer, TradeAnswer { accepted: true });
+/// assert_eq!(answer.kind(), GameMessageKind::TradeAnswer);
+/// assert_eq!(GameMessageKind::Trade as u32, 1);
+/// ```
+#[macro_export]
+macro_rules! message_registry {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident / $kind:ident {
+            $( $variant:ident($payload:ty) $(=> $response:ty)? ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(serde::Serialize, serde::Deserialize, Clone)]
+        $vis enum $name {
+            $( $variant($payload), )*
+        }
+
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        $vis enum $kind {
+            $( $variant, )*
+        }
+
+        impl $name {
+            /// Which variant this is, without its payload.
+            pub fn kind(&self) -> $kind {
+                match self {
+                    $( $name::$variant(_) => $kind::$variant, )*
+                }
+            }
+        }
+
+        $(
+            impl From<$payload> for $name {
+                fn from(payload: $payload) -> Self {
+                    $name::$variant(payload)
+                }
+            }
+
+            impl TryFrom<$name> for $payload {
+                type Error = $name;
+
+                #[allow(unreachable_patterns)]
+                fn try_from(message: $name) -> Result<Self, Self::Error> {
+                    match message {
+                        $name::$variant(payload) => Ok(payload),
+                        other => Err(other),
+                    }
+                }
+            }
+
+            $(
+                impl $crate::prelude::TRequest<$name> for $payload {
+                    type Response = $response;
+                }
+            )?
+        )*
+    };
+}
@@ -0,0 +1,176 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+use matchbox_socket::PeerId;
+
+/// How far past the next packet expected a packet may be numbered and still be held back, which also caps how
+/// many are held per peer and channel. Anything further ahead is dropped, it can't be a packet sent in order.
+const SEQUENCE_WINDOW: u64 = 4096;
+
+/// The packets received from one peer on one channel.
+#[derive(Default)]
+struct Sequence {
+    next: u64,
+    held: BTreeMap<u64, Vec<u8>>,
+    /// Since when packets were held without the one missing before them arriving.
+    stalled_since: Option<Duration>,
+}
+
+/// Puts packets received on `ChannelOrdering::Sequenced` channels back in the order they were sent, either by
+/// holding back the ones that arrive early until those before them arrived, or by dropping the ones that
+/// arrive late.
+#[derive(Default)]
+pub(crate) struct Sequencer {
+    sequences: HashMap<(PeerId, usize), Sequence>,
+}
+
+impl Sequencer {
+    /// Adds packet `seq` from `peer` on `channel`, returning the packets that can be handled now, in order.
+    /// Early packets are held back if `hold`, for reliable channels where the missing ones are still coming,
+    /// otherwise late ones are dropped.
+    pub(crate) fn insert(
+        &mut self,
+        peer: PeerId,
+        channel: usize,
+        seq: u64,
+        packet: Vec<u8>,
+        hold: bool,
+        now: Duration,
+    ) -> Vec<Vec<u8>> {
+        let sequence = self.sequences.entry((peer, channel)).or_default();
+        if seq < sequence.next {
+            // When held, the gap it left was already given up on and it's handled late
+            return match hold {
+                true => vec![packet],
+                false => Vec::new(),
+            };
+        }
+        if seq - sequence.next >= SEQUENCE_WINDOW {
+            return Vec::new();
+        }
+        if !hold {
+            sequence.next = seq.saturating_add(1);
+            return vec![packet];
+        }
+        if seq > sequence.next {
+            sequence.held.entry(seq).or_insert(packet);
+            sequence.stalled_since.get_or_insert(now);
+            return Vec::new();
+        }
+
+        let mut ready = vec![packet];
+        sequence.next = sequence.next.saturating_add(1);
+        while let Some(packet) = sequence.held.remove(&sequence.next) {
+            ready.push(packet);
+            sequence.next = sequence.next.saturating_add(1);
+        }
+        sequence.stalled_since = (!sequence.held.is_empty()).then_some(now);
+        ready
+    }
+
+    /// Stops waiting on packets that have been missing for longer than `timeout`, returning everything that
+    /// was held back behind them, in order.
+    pub(crate) fn release_overdue(&mut self, now: Duration, timeout: Duration) -> Vec<(PeerId, usize, Vec<u8>)> {
+        let mut released = Vec::new();
+        for ((peer, channel), sequence) in self.sequences.iter_mut() {
+            if sequence.stalled_since.is_none_or(|since| now.saturating_sub(since) <= timeout) {
+                continue;
+            }
+            let held = std::mem::take(&mut sequence.held);
+            if let Some(last) = held.keys().next_back() {
+                sequence.next = last.saturating_add(1);
+            }
+            sequence.stalled_since = None;
+            released.extend(held.into_values().map(|packet| (*peer, *channel, packet)));
+        }
+        released
+    }
+
+    /// Drops everything held from `peer`, e.g. because they disconnected.
+    pub(crate) fn forget_peer(&mut self, peer: &PeerId) {
+        self.sequences.retain(|(sender, _), _| sender != peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(n: u128) -> PeerId {
+        PeerId(uuid::Uuid::from_u128(n))
+    }
+
+    const NOW: Duration = Duration::ZERO;
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn holds_early_packets_until_the_gap_fills() {
+        let mut sequencer = Sequencer::default();
+        assert_eq!(sequencer.insert(peer(1), 0, 0, vec![0], true, NOW), vec![vec![0]]);
+        assert!(sequencer.insert(peer(1), 0, 2, vec![2], true, NOW).is_empty());
+        assert!(sequencer.insert(peer(1), 0, 3, vec![3], true, NOW).is_empty());
+        assert_eq!(sequencer.insert(peer(1), 0, 1, vec![1], true, NOW), vec![vec![1], vec![2], vec![3]]);
+        assert_eq!(sequencer.insert(peer(1), 0, 4, vec![4], true, NOW), vec![vec![4]]);
+    }
+
+    #[test]
+    fn keeps_peers_and_channels_apart() {
+        let mut sequencer = Sequencer::default();
+        assert!(sequencer.insert(peer(1), 0, 1, vec![1], true, NOW).is_empty());
+        assert_eq!(sequencer.insert(peer(1), 1, 0, vec![0], true, NOW), vec![vec![0]]);
+        assert_eq!(sequencer.insert(peer(2), 0, 0, vec![0], true, NOW), vec![vec![0]]);
+    }
+
+    #[test]
+    fn keeps_the_first_of_duplicates() {
+        let mut sequencer = Sequencer::default();
+        assert!(sequencer.insert(peer(1), 0, 1, vec![1], true, NOW).is_empty());
+        assert!(sequencer.insert(peer(1), 0, 1, vec![9], true, NOW).is_empty());
+        assert_eq!(sequencer.insert(peer(1), 0, 0, vec![0], true, NOW), vec![vec![0], vec![1]]);
+    }
+
+    #[test]
+    fn releases_the_backlog_after_the_timeout() {
+        let mut sequencer = Sequencer::default();
+        assert!(sequencer.insert(peer(1), 0, 1, vec![1], true, NOW).is_empty());
+        assert!(sequencer.insert(peer(1), 0, 2, vec![2], true, NOW).is_empty());
+        assert!(sequencer.release_overdue(TIMEOUT, TIMEOUT).is_empty());
+
+        let released = sequencer.release_overdue(TIMEOUT * 2, TIMEOUT);
+        assert_eq!(released, vec![(peer(1), 0, vec![1]), (peer(1), 0, vec![2])]);
+        assert_eq!(sequencer.insert(peer(1), 0, 3, vec![3], true, NOW), vec![vec![3]]);
+        // The packet given up on still arrives, late
+        assert_eq!(sequencer.insert(peer(1), 0, 0, vec![0], true, NOW), vec![vec![0]]);
+    }
+
+    #[test]
+    fn drops_packets_past_the_window() {
+        let mut sequencer = Sequencer::default();
+        assert!(sequencer.insert(peer(1), 0, SEQUENCE_WINDOW, vec![1], true, NOW).is_empty());
+        assert!(sequencer.insert(peer(1), 0, u64::MAX, vec![2], true, NOW).is_empty());
+        assert!(sequencer.sequences[&(peer(1), 0)].held.is_empty());
+        assert_eq!(sequencer.insert(peer(1), 0, 0, vec![0], true, NOW), vec![vec![0]]);
+    }
+
+    #[test]
+    fn drops_late_packets_when_not_holding() {
+        let mut sequencer = Sequencer::default();
+        assert_eq!(sequencer.insert(peer(1), 0, 0, vec![0], false, NOW), vec![vec![0]]);
+        assert_eq!(sequencer.insert(peer(1), 0, 2, vec![2], false, NOW), vec![vec![2]]);
+        assert!(sequencer.insert(peer(1), 0, 1, vec![1], false, NOW).is_empty());
+        assert!(sequencer.insert(peer(1), 0, 2, vec![2], false, NOW).is_empty());
+        assert!(sequencer.insert(peer(1), 0, u64::MAX, vec![9], false, NOW).is_empty());
+        assert_eq!(sequencer.insert(peer(1), 0, 3, vec![3], false, NOW), vec![vec![3]]);
+        assert!(sequencer.release_overdue(TIMEOUT * 2, TIMEOUT).is_empty());
+    }
+
+    #[test]
+    fn does_not_overflow_at_the_end_of_the_sequence() {
+        let mut sequencer = Sequencer::default();
+        sequencer.sequences.entry((peer(1), 0)).or_default().next = u64::MAX - 1;
+        assert!(sequencer.insert(peer(1), 0, u64::MAX, vec![1], true, NOW).is_empty());
+        let released = sequencer.release_overdue(TIMEOUT * 2, TIMEOUT);
+        assert_eq!(released, vec![(peer(1), 0, vec![1])]);
+        assert_eq!(sequencer.sequences[&(peer(1), 0)].next, u64::MAX);
+        assert_eq!(sequencer.insert(peer(1), 0, u64::MAX - 1, vec![0], true, NOW), vec![vec![0]]);
+    }
+}
@@ -0,0 +1,15 @@
+use matchbox_socket::PeerId;
+
+/// Signs outgoing packets and checks the signatures on incoming ones, so messages can be trusted to come from
+/// the app level identity a peer claimed, e.g. after a login handshake. Set it with
+/// `NetworkManager::with_signer`.
+///
+/// The crate doesn't pick a scheme, implement this with whatever your app uses, e.g. an HMAC keyed per peer.
+/// Every packet is signed, acks included, and once a signer is set unsigned packets are rejected too.
+pub trait TSigner {
+    /// The signature for `payload` going to `to_peer`.
+    fn sign(&mut self, to_peer: PeerId, payload: &[u8]) -> Vec<u8>;
+
+    /// Whether `signature` is a valid signature of `payload` from `from_peer`.
+    fn verify(&mut self, from_peer: PeerId, payload: &[u8], signature: &[u8]) -> bool;
+}
@@ -0,0 +1,34 @@
+use matchbox_socket::PeerId;
+use crate::prelude::*;
+
+/// A message we were still waiting on acks for when a snapshot was taken. The handlers can't be saved, so
+/// it's up to the app to send it again if it still matters, e.g. with `NetworkManager::resume_pending_ack`.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct PendingAckSnapshot<M> {
+    pub id: MessageId,
+    /// Who it was sent to.
+    pub recipients: Vec<PeerId>,
+    /// Who had acked it so far.
+    pub acked: Vec<PeerId>,
+    pub data: M,
+}
+
+impl<M> PendingAckSnapshot<M> {
+    /// The recipients that hadn't acked yet.
+    pub fn remaining(&self) -> Vec<PeerId> {
+        self.recipients.iter()
+            .filter(|peer| !self.acked.contains(peer))
+            .copied()
+            .collect()
+    }
+}
+
+/// The state the `NetworkManager` keeps for the app, together with state of the app's own, as saved by
+/// `NetworkManager::snapshot` and loaded by `NetworkManager::restore`.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct NetworkSnapshot<S, M> {
+    /// Every peer that had a user.
+    pub peers: Vec<PeerId>,
+    pub pending_acks: Vec<PendingAckSnapshot<M>>,
+    pub app_state: S,
+}
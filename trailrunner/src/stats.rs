@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use matchbox_socket::PeerId;
+use crate::prelude::*;
+
+/// Counts of messages that were given up on, by why.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DroppedStats {
+    /// The message couldn't be serialized.
+    pub serialize_error: u64,
+    /// The message was for a peer that isn't connected.
+    pub no_such_peer: u64,
+    /// The channel refused the message because it had no room left.
+    pub buffer_full: u64,
+    /// The channel was closed, usually because the socket was.
+    pub channel_closed: u64,
+}
+
+impl DroppedStats {
+    /// Every dropped message regardless of the reason, the one number to watch for network health.
+    pub fn total(&self) -> u64 {
+        self.serialize_error + self.no_such_peer + self.buffer_full + self.channel_closed
+    }
+
+    pub(crate) fn record(&mut self, error: &SendError) {
+        match error {
+            SendError::Serialize(_) => self.serialize_error += 1,
+            SendError::NoSuchPeer { .. } => self.no_such_peer += 1,
+            SendError::BufferFull { .. } => self.buffer_full += 1,
+            SendError::ChannelClosed { .. } => self.channel_closed += 1,
+        }
+    }
+}
+
+/// Counters kept by the `NetworkManager` as it runs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NetworkStats {
+    /// Bytes handed to the socket, including acks.
+    pub bytes_sent: u64,
+    /// Bytes read from the socket, including acks.
+    pub bytes_received: u64,
+    /// Messages sent, broadcasts count once for each recipient.
+    pub messages_sent: u64,
+    /// Messages received, not counting acks.
+    pub messages_received: u64,
+    /// Messages that couldn't be delivered. Broadcasts count once for each recipient that failed.
+    pub dropped: DroppedStats,
+    /// Received messages `TApp::receive` reported as unhandled.
+    pub unhandled: u64,
+    /// Acks that arrived for messages we were no longer waiting on, see `TApp::on_orphan_ack`.
+    pub orphan_acks: u64,
+    /// Packets received while paused that didn't fit in the pause buffer.
+    pub dropped_while_paused: u64,
+    /// Packets dropped because they weren't signed or their signature was invalid.
+    pub signature_invalid: u64,
+    /// Queued messages dropped because a later one with the same coalesce key replaced them.
+    pub coalesced: u64,
+    /// Received messages dropped because they were repeats of one already processed.
+    pub duplicates: u64,
+    /// Messages given up on because they weren't acked before their `Message::ack_deadline`, or within the
+    /// retries of `Message::with_ack_timeout`.
+    pub ack_timeouts: u64,
+    /// How long messages sat in the `MessageQueue` before the tick that sent them, as a rolling average.
+    /// Messages queued from `TApp::tick` wait about one tick, much more than that means they're held up, e.g.
+    /// by `pause` or a tick rate too low for what's being sent.
+    pub avg_queue_latency: Duration,
+    /// Messages sent that are still waiting for acks, as of the end of the last tick.
+    pub acks_pending: usize,
+    /// The same counts for each connected peer. A peer's entry goes away once they disconnect.
+    pub peers: HashMap<PeerId, PeerStats>,
+}
+
+impl NetworkStats {
+    pub(crate) fn record_sent(&mut self, peer: PeerId) {
+        self.messages_sent += 1;
+        self.peers.entry(peer).or_default().messages_sent += 1;
+    }
+
+    pub(crate) fn record_received(&mut self, peer: PeerId) {
+        self.messages_received += 1;
+        self.peers.entry(peer).or_default().messages_received += 1;
+    }
+
+    pub(crate) fn record_bytes_sent(&mut self, peer: PeerId, bytes: usize) {
+        self.bytes_sent += bytes as u64;
+        self.peers.entry(peer).or_default().bytes_sent += bytes as u64;
+    }
+
+    pub(crate) fn record_bytes_received(&mut self, peer: PeerId, bytes: usize) {
+        self.bytes_received += bytes as u64;
+        self.peers.entry(peer).or_default().bytes_received += bytes as u64;
+    }
+}
+
+/// Traffic to and from a single peer, see `NetworkManager::peer_stats`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerStats {
+    /// Bytes handed to the socket for them, including acks.
+    pub bytes_sent: u64,
+    /// Bytes read from the socket from them, including acks.
+    pub bytes_received: u64,
+    pub messages_sent: u64,
+    /// Messages received from them, not counting acks.
+    pub messages_received: u64,
+    /// Messages waiting for their ack, as of the end of the last tick.
+    pub acks_pending: usize,
+}
+
+/// What a single `NetworkManager::tick` did.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TickReport {
+    /// Messages sent, broadcasts count once for each recipient.
+    pub messages_sent: u64,
+    /// Messages received, not counting acks.
+    pub messages_received: u64,
+    /// Packets that couldn't be read or sent. The tick carried on past them.
+    pub errors: Vec<NetworkError>,
+}
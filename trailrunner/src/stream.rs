@@ -0,0 +1,83 @@
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use futures::Stream;
+use crate::prelude::*;
+
+/// A message received by the `NetworkManager`, as yielded by a `MessageStream`.
+#[derive(Debug, Clone)]
+pub struct IncomingMessage<M> {
+    pub ctx: ReceiveContext,
+    /// Whether the sender asked for an ack, which `TApp::ack_response` has already answered.
+    pub must_ack: bool,
+    pub data: M,
+}
+
+struct StreamState<M> {
+    messages: VecDeque<IncomingMessage<M>>,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+/// The receiving end of a `MessageStream`, held by the manager.
+pub(crate) struct StreamFeed<M> {
+    state: Arc<Mutex<StreamState<M>>>,
+}
+
+impl<M: Clone> StreamFeed<M> {
+    pub(crate) fn new() -> (Self, MessageStream<M>) {
+        let state = Arc::new(Mutex::new(StreamState {
+            messages: VecDeque::new(),
+            waker: None,
+            closed: false,
+        }));
+        (Self { state: state.clone() }, MessageStream { state })
+    }
+
+    /// Whether the stream on the other end still exists.
+    pub(crate) fn is_connected(&self) -> bool {
+        Arc::strong_count(&self.state) > 1
+    }
+
+    pub(crate) fn push(&self, message: IncomingMessage<M>) {
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        state.messages.push_back(message);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    pub(crate) fn close(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        state.closed = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Yields every message the `NetworkManager` receives, as it processes them in `tick`, for consuming inbound
+/// traffic from async code instead of `TApp::receive`. Create one with `NetworkManager::incoming`.
+///
+/// Messages are still handed to the app as usual. They pile up until read, so keep polling the stream for
+/// as long as it exists. It ends once the manager is closed or dropped.
+pub struct MessageStream<M> {
+    state: Arc<Mutex<StreamState<M>>>,
+}
+
+impl<M> Stream for MessageStream<M> {
+    type Item = IncomingMessage<M>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(message) = state.messages.pop_front() {
+            return Poll::Ready(Some(message));
+        }
+        if state.closed {
+            return Poll::Ready(None);
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
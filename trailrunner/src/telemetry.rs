@@ -0,0 +1,15 @@
+use metrics::{counter, gauge};
+use crate::prelude::*;
+
+/// Mirrors the stats to whatever recorder is installed for the `metrics` crate, e.g. a Prometheus exporter.
+pub(crate) fn publish(stats: &NetworkStats, pending_acks: usize, peer_count: usize) {
+    counter!("trailrunner_bytes_sent_total").absolute(stats.bytes_sent);
+    counter!("trailrunner_bytes_received_total").absolute(stats.bytes_received);
+    counter!("trailrunner_messages_sent_total").absolute(stats.messages_sent);
+    counter!("trailrunner_messages_received_total").absolute(stats.messages_received);
+    counter!("trailrunner_messages_dropped_total").absolute(stats.dropped.total());
+    counter!("trailrunner_messages_unhandled_total").absolute(stats.unhandled);
+    gauge!("trailrunner_pending_acks").set(pending_acks as f64);
+    gauge!("trailrunner_peers").set(peer_count as f64);
+    gauge!("trailrunner_queue_latency_seconds").set(stats.avg_queue_latency.as_secs_f64());
+}
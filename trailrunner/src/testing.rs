@@ -0,0 +1,8 @@
+use matchbox_socket::PeerId;
+use uuid::Uuid;
+
+/// A peer id that is always the same for the same `index`, for tests that need to know which peer is which
+/// without going through signaling. Different indices never give the same id.
+pub fn test_peer_id(index: u128) -> PeerId {
+    PeerId(Uuid::from_u128(index))
+}
@@ -0,0 +1,40 @@
+use std::io::{self, Read};
+use matchbox_socket::PeerId;
+
+/// Identifies a transfer started with `NetworkManager::send_stream`, unique per sender.
+pub type StreamId = u64;
+
+/// How many bytes each chunk of a stream carries unless set with `NetworkManager::with_stream_chunk_size`.
+/// Kept well under what browsers allow in a single data channel message.
+pub const DEFAULT_STREAM_CHUNK_SIZE: usize = 16 * 1024;
+
+/// A stream being sent to a peer, one chunk per tick.
+pub(crate) struct OutgoingStream {
+    pub(crate) id: StreamId,
+    pub(crate) peer: PeerId,
+    reader: Box<dyn Read>,
+    /// A chunk that was read but couldn't be sent yet, because the channel was full.
+    pending: Option<Vec<u8>>,
+}
+
+impl OutgoingStream {
+    pub(crate) fn new(id: StreamId, peer: PeerId, reader: Box<dyn Read>) -> Self {
+        Self { id, peer, reader, pending: None }
+    }
+
+    /// The next chunk to send, up to `chunk_size` bytes, or an empty one once the reader is exhausted.
+    pub(crate) fn next_chunk(&mut self, chunk_size: usize) -> io::Result<Vec<u8>> {
+        if let Some(chunk) = self.pending.take() {
+            return Ok(chunk);
+        }
+
+        let mut chunk = Vec::with_capacity(chunk_size);
+        (&mut self.reader).take(chunk_size as u64).read_to_end(&mut chunk)?;
+        Ok(chunk)
+    }
+
+    /// Puts a chunk that couldn't be sent back, to try again on the next tick.
+    pub(crate) fn hold(&mut self, chunk: Vec<u8>) {
+        self.pending = Some(chunk);
+    }
+}
@@ -6,19 +6,39 @@ pub struct UserList<T: TUser> {
     users: HashMap<PeerId, T>,
 }
 
+impl <T: TUser> Default for UserList<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl <T: TUser> UserList<T> {
     pub fn new() -> Self {
         Self { users: HashMap::new() }
     }
 
-    pub(crate) fn insert(&mut self, peer_id: PeerId, user: T) {
+    /// Adds the user for `peer_id`, replacing any they had. The manager does this itself unless it was built
+    /// `with_manual_users`.
+    pub fn insert(&mut self, peer_id: PeerId, user: T) {
         self.users.insert(peer_id, user);
     }
 
-    pub(crate) fn remove(&mut self, peer_id: &PeerId) -> Option<T> {
+    pub fn remove(&mut self, peer_id: &PeerId) -> Option<T> {
         self.users.remove(peer_id)
     }
-    
+
+    /// Moves the user of `old` over to `new`, for a peer that reconnected under a new id while we still had
+    /// their old user, so their state carries over. Any user `new` already had is replaced. Returns whether
+    /// `old` had a user to move.
+    pub fn migrate(&mut self, old: &PeerId, new: PeerId) -> bool {
+        let Some(mut user) = self.users.remove(old) else {
+            return false;
+        };
+        user.on_migrated(new);
+        self.users.insert(new, user);
+        true
+    }
+
     pub fn get(&self, peer_id: &PeerId) -> Option<&T> {
         self.users.get(peer_id)
     }
@@ -26,8 +46,62 @@ impl <T: TUser> UserList<T> {
     pub fn get_mut(&mut self, peer_id: &PeerId) -> Option<&mut T> {
         self.users.get_mut(peer_id)
     }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    /// The peer id of every user, in no particular order.
+    pub fn keys(&self) -> impl Iterator<Item = &PeerId> {
+        self.users.keys()
+    }
+
+    /// Every user with their peer id, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&PeerId, &T)> {
+        self.users.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&PeerId, &mut T)> {
+        self.users.iter_mut()
+    }
+
+    /// Every user with their peer id, ordered by `key`, e.g. score for a leaderboard or seat for turn order.
+    /// Users with the same key are ordered by peer id so the order is the same on every call.
+    pub fn sorted_by<K: Ord>(&self, mut key: impl FnMut(&T) -> K) -> Vec<(&PeerId, &T)> {
+        let mut users: Vec<_> = self.users.iter().collect();
+        users.sort_by_cached_key(|(peer_id, user)| (key(user), **peer_id));
+        users
+    }
+}
+
+impl<'a, T: TUser> IntoIterator for &'a UserList<T> {
+    type Item = (&'a PeerId, &'a T);
+    type IntoIter = std::collections::hash_map::Iter<'a, PeerId, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.users.iter()
+    }
 }
 
 pub trait TUser: Debug + Clone {
     fn new(peer_id: PeerId) -> Self;
+
+    /// Creates the user of a peer that introduced themselves with `meta`, the hello they set with
+    /// `NetworkManager::with_hello`, e.g. a bincode encoded name and team. Falls back to `new` by default.
+    fn new_with_meta(peer_id: PeerId, _meta: &[u8]) -> Self {
+        Self::new(peer_id)
+    }
+
+    /// Called when the user is moved over to a new peer id by `UserList::migrate`, update any copy of the
+    /// peer id kept in the user here.
+    fn on_migrated(&mut self, _new_peer_id: PeerId) {}
+
+    /// App defined tags or roles of the user, e.g. "spectator", for `Message::to_tag`. None by default.
+    fn tags(&self) -> &[String] {
+        &[]
+    }
 }